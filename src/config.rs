@@ -0,0 +1,472 @@
+//! Server options read from a TOML file. Every key is optional and falls back to the default,
+//! or to the command line when given there too. Unknown keys are rejected, so typos don't go
+//! unnoticed:
+//!
+//! ```toml
+//! bind = ["127.0.0.1", "::1"]
+//! port = 8080
+//! directory = "files"         # relative to the configuration file
+//!
+//! [limits]
+//! max_body_size = "16M"       # a number of bytes, or with a K, M or G suffix
+//!
+//! [timeouts]
+//! idle = "30s"                # a number of seconds, or with an ms, s or m suffix
+//!
+//! [compression]
+//! enabled = true
+//! level = 6                   # from 0 (fastest) to 9 (smallest)
+//! min_size = "1K"
+//! decode_requests = true      # decompress request bodies sent with a content coding
+//! max_decoded_size = "64M"
+//! max_decode_ratio = 100
+//!
+//! [files]
+//! symlinks = "within-root"    # or "deny" or "follow"
+//! sniff = true                # guess the type of files without an extension
+//! listings = true             # list the contents of directories
+//! show_hidden = false         # include dot files in listings
+//! sendfile = true             # send file contents with sendfile(2), on Linux
+//! fsync = true                # sync uploads to disk before answering
+//! create_dirs = true          # create missing directories for uploads
+//! webdav = true               # handle WebDAV methods, to mount the root directory
+//!
+//! [files.mime_types]
+//! log = "text/plain"
+//!
+//! [logging]
+//! level = "debug"
+//! ```
+
+use crate::{
+    log::Level,
+    options::{self, OptionsError, ServerOptions, SymlinkPolicy},
+};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    bind: Option<Addresses>,
+    port: Option<u16>,
+    directory: Option<PathBuf>,
+    #[serde(default)]
+    limits: LimitsSection,
+    #[serde(default)]
+    timeouts: TimeoutsSection,
+    #[serde(default)]
+    compression: CompressionSection,
+    #[serde(default)]
+    files: FilesSection,
+    #[serde(default)]
+    logging: LoggingSection,
+    /// Directory the file was read from, which relative paths are resolved against.
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsSection {
+    max_request_line: Option<Size>,
+    max_header_size: Option<Size>,
+    max_headers: Option<usize>,
+    max_body_size: Option<Size>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsSection {
+    idle: Option<Seconds>,
+    headers: Option<Seconds>,
+    min_body_rate: Option<Size>,
+    body_grace: Option<Seconds>,
+    shutdown: Option<Seconds>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionSection {
+    enabled: Option<bool>,
+    level: Option<CompressionLevel>,
+    min_size: Option<Size>,
+    decode_requests: Option<bool>,
+    max_decoded_size: Option<Size>,
+    max_decode_ratio: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilesSection {
+    symlinks: Option<Parsed<SymlinkPolicy>>,
+    sniff: Option<bool>,
+    listings: Option<bool>,
+    show_hidden: Option<bool>,
+    sendfile: Option<bool>,
+    fsync: Option<bool>,
+    create_dirs: Option<bool>,
+    webdav: Option<bool>,
+    #[serde(default)]
+    mime_types: MimeTypes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoggingSection {
+    level: Option<Parsed<Level>>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, OptionsError> {
+        let invalid = |reason: String| OptionsError::InvalidConfig {
+            path: path.to_path_buf(),
+            reason,
+        };
+
+        let text = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let mut config = Self::parse(&text).map_err(invalid)?;
+        config.base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Ok(config)
+    }
+
+    /// Parses the contents of a configuration file. Errors say which line they are on.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|err| err.to_string().trim_end().to_string())
+    }
+
+    /// Overrides `options` with everything the file sets.
+    pub fn apply(self, options: &mut ServerOptions) -> Result<(), OptionsError> {
+        if let Some(Addresses(bind)) = self.bind {
+            options.bind = bind;
+        }
+        if let Some(port) = self.port {
+            options.port = port;
+        }
+        if let Some(directory) = self.directory {
+            options.root = Some(options::directory(&self.base.join(directory))?);
+        }
+
+        let limits = &mut options.limits;
+        set(&mut limits.max_request_line, self.limits.max_request_line);
+        set(&mut limits.max_header_size, self.limits.max_header_size);
+        set(&mut limits.max_headers, self.limits.max_headers);
+        set(&mut limits.max_body_size, self.limits.max_body_size);
+
+        let timeouts = &mut options.timeouts;
+        set(&mut timeouts.idle, self.timeouts.idle);
+        set(&mut timeouts.headers, self.timeouts.headers);
+        if let Some(Size(rate)) = self.timeouts.min_body_rate {
+            timeouts.min_body_rate = rate as u64;
+        }
+        set(&mut timeouts.body_grace, self.timeouts.body_grace);
+        set(&mut timeouts.shutdown, self.timeouts.shutdown);
+
+        let compression = &mut options.compression;
+        set(&mut compression.enabled, self.compression.enabled);
+        if let Some(CompressionLevel(level)) = self.compression.level {
+            compression.level = level;
+        }
+        set(&mut compression.min_size, self.compression.min_size);
+        set(
+            &mut compression.decode_requests,
+            self.compression.decode_requests,
+        );
+        set(
+            &mut compression.max_decoded_size,
+            self.compression.max_decoded_size,
+        );
+        set(
+            &mut compression.max_decode_ratio,
+            self.compression.max_decode_ratio,
+        );
+        if let Some(Parsed(symlinks)) = self.files.symlinks {
+            options.files.symlinks = symlinks;
+        }
+        set(&mut options.files.sniff, self.files.sniff);
+        set(&mut options.files.listings, self.files.listings);
+        set(&mut options.files.show_hidden, self.files.show_hidden);
+        set(&mut options.files.sendfile, self.files.sendfile);
+        set(&mut options.files.fsync, self.files.fsync);
+        set(&mut options.files.create_dirs, self.files.create_dirs);
+        set(&mut options.files.webdav, self.files.webdav);
+        options.files.mime_types.extend(self.files.mime_types.0);
+        if let Some(Parsed(level)) = self.logging.level {
+            options.log_level = level;
+        }
+
+        Ok(())
+    }
+}
+
+fn set<T, V: Into<T>>(option: &mut T, value: Option<V>) {
+    if let Some(value) = value {
+        *option = value.into();
+    }
+}
+
+/// A single address or a list of them.
+#[derive(Debug)]
+struct Addresses(Vec<IpAddr>);
+
+impl<'de> Deserialize<'de> for Addresses {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(Parsed<IpAddr>),
+            Many(Vec<Parsed<IpAddr>>),
+        }
+
+        let addresses = match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(address) => vec![address.0],
+            OneOrMany::Many(addresses) => addresses.into_iter().map(|a| a.0).collect(),
+        };
+        if addresses.is_empty() {
+            return Err(de::Error::custom("expected at least one address"));
+        }
+        Ok(Self(addresses))
+    }
+}
+
+/// Media types by file extension.
+#[derive(Debug, Default)]
+struct MimeTypes(HashMap<String, String>);
+
+impl<'de> Deserialize<'de> for MimeTypes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::<String, String>::deserialize(deserializer)?
+            .iter()
+            .map(|(extension, media_type)| options::mime_type(extension, media_type))
+            .collect::<Result<_, _>>()
+            .map(Self)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A value that's written as a string in the file and parsed with [`FromStr`].
+#[derive(Debug)]
+struct Parsed<T>(T);
+
+impl<'de, T> Deserialize<'de> for Parsed<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map(Self).map_err(de::Error::custom)
+    }
+}
+
+/// A compression level, checked like the command line's.
+#[derive(Debug)]
+struct CompressionLevel(u32);
+
+impl<'de> Deserialize<'de> for CompressionLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let level = u32::deserialize(deserializer)?;
+        options::level(&level.to_string())
+            .map(Self)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A number, or a string with a unit suffix.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(u64),
+    Text(String),
+}
+
+/// A number of bytes, like the command line's SIZE.
+#[derive(Debug)]
+struct Size(usize);
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match NumberOrText::deserialize(deserializer)? {
+            NumberOrText::Number(n) => usize::try_from(n).map(Self).map_err(de::Error::custom),
+            NumberOrText::Text(text) => options::size(&text).map(Self).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl From<Size> for usize {
+    fn from(value: Size) -> Self {
+        value.0
+    }
+}
+
+/// A duration, like the command line's DURATION.
+#[derive(Debug)]
+struct Seconds(Duration);
+
+impl<'de> Deserialize<'de> for Seconds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match NumberOrText::deserialize(deserializer)? {
+            NumberOrText::Number(n) => options::duration(&n.to_string())
+                .map(Self)
+                .map_err(de::Error::custom),
+            NumberOrText::Text(text) => options::duration(&text)
+                .map(Self)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+impl From<Seconds> for Duration {
+    fn from(value: Seconds) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(text: &str) -> ServerOptions {
+        let mut options = ServerOptions::default();
+        ConfigFile::parse(text)
+            .unwrap()
+            .apply(&mut options)
+            .unwrap();
+        options
+    }
+
+    #[test]
+    fn parse_empty_file() {
+        let options = apply("");
+        assert_eq!(options.addrs(), ServerOptions::default().addrs());
+    }
+
+    #[test]
+    fn parse_all_sections() {
+        let options = apply(
+            r#"
+            bind = ["127.0.0.1", "::1"]
+            port = 8080
+            directory = "."
+
+            [limits]
+            max_request_line = "4K"
+            max_headers = 20
+            max_body_size = 1048576
+
+            [timeouts]
+            idle = "500ms"
+            headers = 5
+            min_body_rate = "2K"
+
+            [compression]
+            enabled = false
+            level = 1
+            min_size = "1K"
+
+            [files]
+            sniff = true
+            listings = true
+            fsync = true
+
+            [files.mime_types]
+            ".Log" = "text/plain"
+
+            [logging]
+            level = "debug"
+            "#,
+        );
+
+        assert_eq!(
+            options.addrs(),
+            vec![
+                "127.0.0.1:8080".parse().unwrap(),
+                "[::1]:8080".parse().unwrap()
+            ]
+        );
+        assert_eq!(options.root, Some(Path::new(".").canonicalize().unwrap()));
+        assert_eq!(options.limits.max_request_line, 4096);
+        assert_eq!(options.limits.max_headers, 20);
+        assert_eq!(options.limits.max_body_size, 1 << 20);
+        assert_eq!(options.limits.max_header_size, 64 * 1024);
+        assert_eq!(options.timeouts.idle, Duration::from_millis(500));
+        assert_eq!(options.timeouts.headers, Duration::from_secs(5));
+        assert_eq!(options.timeouts.min_body_rate, 2048);
+        assert!(!options.compression.enabled);
+        assert_eq!(options.compression.level, 1);
+        assert_eq!(options.compression.min_size, 1024);
+        assert!(options.files.sniff);
+        assert!(options.files.listings);
+        assert!(!options.files.show_hidden);
+        assert!(options.files.fsync);
+        assert_eq!(options.files.mime_types["log"], "text/plain");
+        assert_eq!(options.log_level, Level::Debug);
+    }
+
+    #[test]
+    fn parse_single_address() {
+        let options = apply(r#"bind = "0.0.0.0""#);
+        assert_eq!(options.addrs(), vec!["0.0.0.0:4221".parse().unwrap()]);
+    }
+
+    #[test]
+    fn reject_unknown_keys_with_line() {
+        let err = ConfigFile::parse("port = 80\n\n[limits]\nmax_body = 10\n").unwrap_err();
+        assert!(err.contains("line 4"), "{}", err);
+        assert!(err.contains("max_body"), "{}", err);
+
+        let err = ConfigFile::parse("port = 80\nfrobnicate = true\n").unwrap_err();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn reject_invalid_values() {
+        let cases = [
+            "port = 65536",
+            "port = \"80\"",
+            "bind = []",
+            "bind = \"localhost\"",
+            "[limits]\nmax_body_size = \"12T\"",
+            "[timeouts]\nidle = \"1h\"",
+            "[timeouts]\nidle = 99999999999999",
+            "[logging]\nlevel = \"loud\"",
+            "[compression]\nenabled = \"yes\"",
+            "[compression]\nlevel = 12",
+            "[files.mime_types]\ntxt = \"plain\"",
+        ];
+
+        for text in cases {
+            assert!(ConfigFile::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let dir = std::env::temp_dir().join(format!("config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        fs::write(
+            &path,
+            "bind = [\"::1\"]\nport = 8080\n[logging]\nlevel = \"warn\"\n",
+        )
+        .unwrap();
+
+        let args = ["--port", "9090", "--config", path.to_str().unwrap()].map(String::from);
+        let options = match ServerOptions::from_args(args).unwrap() {
+            options::Command::Serve(options) => options,
+            other => panic!("expected options, got {:?}", other),
+        };
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(options.addrs(), vec!["[::1]:9090".parse().unwrap()]);
+        assert_eq!(options.log_level, Level::Warn);
+    }
+}
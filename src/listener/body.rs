@@ -0,0 +1,403 @@
+//! Response content, which is either in memory or read from a file or a producing task while
+//! it's being written, so that large responses don't have to fit in memory.
+
+use bytes::Bytes;
+use std::{future::Future, io, ops::Range};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+/// Most bytes read from a file or sent by a producing task at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks a producing task can get ahead of the client.
+const STREAM_BOUND: usize = 4;
+
+#[derive(Debug)]
+pub enum Body {
+    /// Content that's already in memory.
+    Full(Vec<u8>),
+    /// A range of a file, read as it's written.
+    File { file: File, range: Range<u64> },
+    /// Chunks sent by a task as they're produced, `len` bytes in all if that's known.
+    Stream {
+        len: Option<u64>,
+        chunks: mpsc::Receiver<io::Result<Chunk>>,
+    },
+}
+
+/// What a task producing a streamed body sends.
+#[derive(Debug)]
+pub enum Chunk {
+    Data(Bytes),
+    /// Fields to send after the content, if it's sent in chunks.
+    Trailers(Vec<(String, String)>),
+}
+
+impl Body {
+    /// The length of the content, unless it's streamed without knowing it up front.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Self::Full(content) => Some(content.len() as u64),
+            Self::File { range, .. } => Some(range.end - range.start),
+            Self::Stream { len, .. } => *len,
+        }
+    }
+
+    /// Whether there's known to be no content.
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// The content, if it's in memory.
+    #[cfg(test)]
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Full(content) => Some(content),
+            _ => None,
+        }
+    }
+
+    /// Writes the content to `writer` a piece at a time, so at most one piece of it is held in
+    /// memory and it's only read as fast as the client receives it. With `chunked`, each piece
+    /// is framed as a chunk (RFC 9112 section 7.1) and the content ends with the last chunk and
+    /// any trailers, which are dropped otherwise.
+    ///
+    /// Fails if a file or stream ends up shorter or longer than it was said to be, as the
+    /// response can't be corrected once its length is sent.
+    pub async fn write_to<W: AsyncWrite + Unpin>(
+        self,
+        writer: &mut W,
+        chunked: bool,
+    ) -> io::Result<()> {
+        let mut trailers = Vec::new();
+
+        match self {
+            Self::Full(content) => write_data(writer, &content, chunked).await?,
+            Self::File { mut file, range } => {
+                file.seek(io::SeekFrom::Start(range.start)).await?;
+                let mut remaining = range.end - range.start;
+                let mut buffer = vec![0; CHUNK_SIZE.min(remaining as usize)];
+
+                while remaining > 0 {
+                    let size = buffer.len().min(remaining as usize);
+                    let read = file.read(&mut buffer[..size]).await?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "file is shorter than its length",
+                        ));
+                    }
+                    write_data(writer, &buffer[..read], chunked).await?;
+                    remaining -= read as u64;
+                }
+            }
+            Self::Stream { len, mut chunks } => {
+                let mut written = 0;
+                while let Some(chunk) = chunks.recv().await {
+                    match chunk? {
+                        Chunk::Data(data) => {
+                            written += data.len() as u64;
+                            if len.is_some_and(|len| written > len) {
+                                break;
+                            }
+                            write_data(writer, &data, chunked).await?;
+                        }
+                        Chunk::Trailers(fields) => trailers = fields,
+                    }
+                }
+
+                if len.is_some_and(|len| written != len) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "streamed content doesn't match its length",
+                    ));
+                }
+            }
+        }
+
+        if chunked {
+            let mut end = "0\r\n".to_string();
+            for (name, value) in trailers {
+                end.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            end.push_str("\r\n");
+            writer.write_all(end.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole content into memory.
+    #[cfg(test)]
+    pub async fn collect(self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.write_to(&mut content, false).await?;
+        Ok(content)
+    }
+}
+
+/// Writes a piece of content, as a chunk if `chunked`. Empty pieces are skipped, as an empty
+/// chunk would end the content.
+async fn write_data<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    chunked: bool,
+) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    if !chunked {
+        return writer.write_all(data).await;
+    }
+
+    writer
+        .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+        .await?;
+    writer.write_all(data).await?;
+    writer.write_all(b"\r\n").await
+}
+
+/// Compares content in memory, for tests.
+#[cfg(test)]
+impl<const N: usize> PartialEq<&[u8; N]> for Body {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self.bytes() == Some(&other[..])
+    }
+}
+
+/// Sends the chunks of a streamed body.
+pub struct ChunkSender(mpsc::Sender<io::Result<Chunk>>);
+
+impl ChunkSender {
+    /// Waits for room for the chunk, failing if the body is no longer being written.
+    pub async fn send(&self, data: Bytes) -> io::Result<()> {
+        self.send_chunk(Chunk::Data(data)).await
+    }
+
+    /// Sets fields to send after the content. They're only sent if the content is sent in
+    /// chunks, which it is to HTTP/1.1 clients when its length isn't known.
+    #[allow(dead_code)] // for handlers that produce content, none of which have trailers yet
+    pub async fn send_trailers(&self, fields: Vec<(String, String)>) -> io::Result<()> {
+        self.send_chunk(Chunk::Trailers(fields)).await
+    }
+
+    /// Sends a range of a file, a chunk at a time.
+    pub async fn send_file(&self, file: &mut File, range: Range<u64>) -> io::Result<()> {
+        file.seek(io::SeekFrom::Start(range.start)).await?;
+        let mut remaining = range.end - range.start;
+
+        while remaining > 0 {
+            let mut chunk = vec![0; CHUNK_SIZE.min(remaining as usize)];
+            file.read_exact(&mut chunk).await?;
+            remaining -= chunk.len() as u64;
+            self.send(chunk.into()).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_chunk(&self, chunk: Chunk) -> io::Result<()> {
+        self.0
+            .send(Ok(chunk))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "body no longer written"))
+    }
+}
+
+/// A body sent by `produce`, which runs as a task of its own, of `len` bytes if that's known.
+/// An error it returns is passed on to the writer, which closes the connection as the response
+/// can't be completed.
+pub fn stream<F, Fut>(len: Option<u64>, produce: F) -> Body
+where
+    F: FnOnce(ChunkSender) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let (tx, chunks) = mpsc::channel(STREAM_BOUND);
+    let producing = produce(ChunkSender(tx.clone()));
+
+    tokio::spawn(async move {
+        if let Err(err) = producing.await {
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+
+    Body::Stream { len, chunks }
+}
+
+/// Writes a range of a file to a socket with `sendfile(2)`, so its content is copied by the
+/// kernel instead of going through user space.
+#[cfg(target_os = "linux")]
+pub async fn sendfile(
+    socket: &tokio::net::TcpStream,
+    file: &File,
+    range: Range<u64>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let mut offset = range.start as libc::off_t;
+    let end = range.end as libc::off_t;
+
+    while offset < end {
+        let count = ((end - offset) as usize).min(CHUNK_SIZE * 16);
+        socket.writable().await?;
+        let sent = socket.try_io(Interest::WRITABLE, || {
+            // SAFETY: both descriptors stay open for the call, and `offset` is a valid pointer
+            // that sendfile advances past the bytes sent.
+            let sent =
+                unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+            match sent {
+                -1 => Err(io::Error::last_os_error()),
+                sent => Ok(sent as usize),
+            }
+        });
+
+        match sent {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file is shorter than its length",
+                ))
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::testing::TempDir;
+
+    /// Content longer than a few chunks, which doesn't repeat at chunk boundaries.
+    fn content() -> Vec<u8> {
+        (0..CHUNK_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn write_file_ranges() {
+        let content = content();
+        let dir = TempDir::new("ranges");
+        let file = dir.write("file", &content);
+
+        let range = 0..content.len() as u64;
+        let body = Body::File {
+            file: File::open(&file).await.unwrap(),
+            range: range.clone(),
+        };
+        assert_eq!(body.len(), Some(range.end));
+        assert_eq!(body.collect().await.unwrap(), content);
+
+        let body = Body::File {
+            file: File::open(&file).await.unwrap(),
+            range: 100..CHUNK_SIZE as u64 * 2,
+        };
+        assert_eq!(body.collect().await.unwrap(), &content[100..CHUNK_SIZE * 2]);
+
+        // the file is shorter than the range
+        let body = Body::File {
+            file: File::open(&file).await.unwrap(),
+            range: 0..content.len() as u64 + 1,
+        };
+        let err = body.collect().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn write_streams() {
+        let body = stream(Some(11), |sender| async move {
+            sender.send(Bytes::from("hello ")).await?;
+            sender.send(Bytes::from("world")).await
+        });
+        assert_eq!(body.collect().await.unwrap(), b"hello world");
+
+        let content = content();
+        let dir = TempDir::new("stream");
+        let file = dir.write("file", &content);
+        let mut opened = File::open(&file).await.unwrap();
+        let body = stream(Some(content.len() as u64), |sender| async move {
+            sender.send_file(&mut opened, 0..100).await?;
+            sender
+                .send_file(&mut opened, 100..content.len() as u64)
+                .await
+        });
+        assert_eq!(body.collect().await.unwrap(), self::content());
+    }
+
+    #[tokio::test]
+    async fn reject_streams_of_wrong_length() {
+        for len in [4, 6] {
+            let body = stream(Some(len), |sender| async move {
+                sender.send(Bytes::from("hello")).await
+            });
+            let err = body.collect().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", len);
+        }
+
+        let body = stream(None, |_| async { Err(io::Error::other("failed")) });
+        assert_eq!(body.collect().await.unwrap_err().to_string(), "failed");
+    }
+
+    #[tokio::test]
+    async fn write_chunks() {
+        async fn chunked(body: Body) -> String {
+            let mut written = Vec::new();
+            body.write_to(&mut written, true).await.unwrap();
+            String::from_utf8(written).unwrap()
+        }
+
+        let body = Body::Full(b"hello".to_vec());
+        assert_eq!(chunked(body).await, "5\r\nhello\r\n0\r\n\r\n");
+        assert_eq!(chunked(Body::Full(vec![])).await, "0\r\n\r\n");
+
+        let body = stream(None, |sender| async move {
+            sender.send(Bytes::from("hello ")).await?;
+            sender.send(Bytes::new()).await?;
+            sender.send(Bytes::from("chunked world")).await?;
+            let trailers = vec![("checksum".to_string(), "1234".to_string())];
+            sender.send_trailers(trailers).await
+        });
+        assert_eq!(
+            chunked(body).await,
+            "6\r\nhello \r\nd\r\nchunked world\r\n0\r\nchecksum: 1234\r\n\r\n"
+        );
+
+        // trailers are dropped without chunks
+        let body = stream(None, |sender| async move {
+            sender.send(Bytes::from("hello")).await?;
+            sender
+                .send_trailers(vec![("a".to_string(), "b".to_string())])
+                .await
+        });
+        assert_eq!(body.collect().await.unwrap(), b"hello");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn send_files() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let content = content();
+        let dir = TempDir::new("sendfile");
+        let file = dir.write("file", &content);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let opened = File::open(&file).await.unwrap();
+        let sending =
+            tokio::spawn(async move { sendfile(&server, &opened, 10..content.len() as u64).await });
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        sending.await.unwrap().unwrap();
+        assert_eq!(received, &self::content()[10..]);
+    }
+}
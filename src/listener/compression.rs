@@ -0,0 +1,793 @@
+//! Content codings the server can compress responses with, and negotiating which one to use
+//! from the request's `Accept-Encoding` field (RFC 9110 section 12.5.3).
+
+use crate::{
+    listener::{
+        body::{self, Body},
+        headers::Headers,
+        request::{HttpRequest, HttpRequestError, RequestBody},
+        HttpResponse,
+    },
+    log,
+    options::CompressionOptions,
+};
+use brotli::enc::{
+    encode::{
+        BrotliEncoderCompressStream, BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance,
+        BrotliEncoderHasMoreOutput, BrotliEncoderIsFinished, BrotliEncoderOperation,
+        BrotliEncoderParameter, BrotliEncoderSetParameter, BrotliEncoderStateStruct,
+    },
+    StandardAlloc,
+};
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+};
+use tokio::io::AsyncReadExt;
+
+/// Supported codings, most preferred first for when the client likes several equally.
+pub const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+/// Supported codings, as listed in an `Accept-Encoding` field.
+pub const SUPPORTED_CODINGS: &str = "br, gzip, deflate";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// The name used for the coding in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+        }
+    }
+
+    /// Compresses `content` at `level`, from 0 (fastest) to 9 (smallest).
+    pub fn compress(self, content: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(self, level);
+        let mut compressed = encoder.write(content)?;
+        compressed.extend(encoder.finish()?);
+        Ok(compressed)
+    }
+
+    /// Decompresses `content`, failing if the result would be larger than `limit`.
+    pub fn decompress(self, content: &[u8], limit: usize) -> Result<Vec<u8>, HttpRequestError> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Self::Gzip => Box::new(GzDecoder::new(content)),
+            Self::Deflate => Box::new(ZlibDecoder::new(content)),
+            Self::Brotli => Box::new(brotli::Decompressor::new(content, 4096)),
+        };
+
+        // reading one byte past the limit tells a body that's exactly at it from a larger one
+        let mut output = Vec::with_capacity(content.len().saturating_mul(4).min(limit));
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut output)
+            .map_err(|_| HttpRequestError::CorruptContent(self.name()))?;
+
+        if output.len() > limit {
+            return Err(HttpRequestError::DecodedPayloadTooLarge);
+        }
+        Ok(output)
+    }
+}
+
+/// Compresses content that's given a piece at a time, handing out what it has compressed so
+/// far after each piece.
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    // "deflate" means the zlib format, not a raw deflate stream (RFC 9110 section 8.4.1.2)
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Brotli(Box<BrotliEncoder>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, level: u32) -> Self {
+        match encoding {
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::new(level))),
+            Encoding::Deflate => {
+                Self::Deflate(ZlibEncoder::new(Vec::new(), Compression::new(level)))
+            }
+            Encoding::Brotli => Self::Brotli(Box::new(BrotliEncoder::new(level))),
+        }
+    }
+
+    fn write(&mut self, content: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(content)?;
+                encoder.get_mut()
+            }
+            Self::Deflate(encoder) => {
+                encoder.write_all(content)?;
+                encoder.get_mut()
+            }
+            Self::Brotli(encoder) => {
+                let mut output = Vec::new();
+                encoder.run(
+                    BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+                    content,
+                    &mut output,
+                )?;
+                return Ok(output);
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    /// Ends the compressed stream, returning the rest of it.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Deflate(encoder) => encoder.finish(),
+            Self::Brotli(mut encoder) => {
+                let mut output = Vec::new();
+                encoder.run(
+                    BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+                    &[],
+                    &mut output,
+                )?;
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// A brotli encoder used through the library's streaming interface, since its
+/// `CompressorWriter` ignores any failure to end the stream.
+struct BrotliEncoder(BrotliEncoderStateStruct<StandardAlloc>);
+
+impl BrotliEncoder {
+    fn new(level: u32) -> Self {
+        let mut state = BrotliEncoderCreateInstance(StandardAlloc::default());
+        BrotliEncoderSetParameter(
+            &mut state,
+            BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
+            level,
+        );
+        BrotliEncoderSetParameter(&mut state, BrotliEncoderParameter::BROTLI_PARAM_LGWIN, 22);
+        Self(state)
+    }
+
+    /// Compresses `input` with `op`, appending the output to `output`. Finishing goes on until
+    /// the stream has ended, anything else until all of `input` has been taken.
+    fn run(
+        &mut self,
+        op: BrotliEncoderOperation,
+        input: &[u8],
+        output: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let mut buffer = [0; 4096];
+        let (mut available_in, mut input_offset) = (input.len(), 0);
+
+        loop {
+            let (mut available_out, mut output_offset) = (buffer.len(), 0);
+            let result = BrotliEncoderCompressStream(
+                &mut self.0,
+                op,
+                &mut available_in,
+                input,
+                &mut input_offset,
+                &mut available_out,
+                &mut buffer,
+                &mut output_offset,
+                &mut None,
+                &mut |_, _, _, _| (),
+            );
+            if result <= 0 {
+                return Err(io::Error::other("brotli compression failed"));
+            }
+            output.extend_from_slice(&buffer[..output_offset]);
+
+            let done = match op {
+                BrotliEncoderOperation::BROTLI_OPERATION_FINISH => {
+                    BrotliEncoderIsFinished(&self.0) != 0
+                }
+                _ => available_in == 0 && BrotliEncoderHasMoreOutput(&self.0) == 0,
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Drop for BrotliEncoder {
+    fn drop(&mut self) {
+        BrotliEncoderDestroyInstance(&mut self.0);
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            "deflate" => Ok(Self::Deflate),
+            "br" => Ok(Self::Brotli),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The codings a client accepts, with their quality values in thousandths.
+#[derive(Debug, Default)]
+pub struct AcceptEncoding(Vec<(String, u16)>);
+
+impl AcceptEncoding {
+    pub fn from_headers(headers: &Headers) -> Self {
+        Self::parse(headers.get_list("accept-encoding"))
+    }
+
+    /// Parses the elements of `Accept-Encoding` fields. Elements with an invalid quality
+    /// value are ignored.
+    pub fn parse<'a>(elements: impl Iterator<Item = &'a str>) -> Self {
+        let codings = elements.filter_map(weighted).collect();
+
+        Self(codings)
+    }
+
+    /// The quality of `coding`, if the client gave one for it or for `*`.
+    fn quality(&self, coding: &str) -> Option<u16> {
+        let find = |name: &str| self.0.iter().find(|(c, _)| c == name).map(|(_, q)| *q);
+        find(coding).or_else(|| find("*"))
+    }
+
+    /// Whether the content may be sent without any coding. It may unless the client explicitly
+    /// refused it with a quality of zero.
+    pub fn accepts_identity(&self) -> bool {
+        self.quality("identity") != Some(0)
+    }
+
+    /// The coding the client prefers among those supported, unless it explicitly gave unencoded
+    /// content a higher quality. Codings it didn't mention aren't acceptable.
+    pub fn preferred(&self) -> Option<Encoding> {
+        let identity = self
+            .0
+            .iter()
+            .find(|(coding, _)| coding == "identity")
+            .map_or(0, |(_, quality)| *quality);
+        let mut best: Option<(Encoding, u16)> = None;
+
+        for encoding in ENCODINGS {
+            let quality = self.quality(encoding.name()).unwrap_or(0);
+            if quality > best.map_or(0, |(_, q)| q) {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.filter(|(_, quality)| *quality >= identity)
+            .map(|(encoding, _)| encoding)
+    }
+}
+
+/// Splits an element of an `Accept`-style field into its token, lowercased, and its quality
+/// value in thousandths, one by default. Returns `None` if the quality value is invalid.
+pub fn weighted(element: &str) -> Option<(String, u16)> {
+    let mut params = element.split(';');
+    let token = params.next()?.trim().to_ascii_lowercase();
+    let mut quality = 1000;
+    for param in params {
+        match param.split_once('=') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("q") => {
+                quality = qvalue(value.trim())?;
+            }
+            _ => {}
+        }
+    }
+    Some((token, quality))
+}
+
+/// Parses a quality value, which has at most three decimals and is no more than one.
+fn qvalue(value: &str) -> Option<u16> {
+    let (integer, decimals) = value.split_once('.').unwrap_or((value, ""));
+    if !matches!(integer, "0" | "1")
+        || decimals.len() > 3
+        || !decimals.bytes().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let thousandths = format!("{}{:0<3}", integer, decimals).parse().ok()?;
+    (thousandths <= 1000).then_some(thousandths)
+}
+
+/// Removes the codings listed in a request's `Content-Encoding` field from its body, if
+/// decoding is enabled. The decoded body may be no larger than `max_decoded_size`, nor more
+/// than `max_decode_ratio` times the size of the encoded one, so a small body can't expand to
+/// fill up memory. Encoded bodies are read into memory, then decoded on a blocking thread.
+pub async fn decode_request(
+    options: &CompressionOptions,
+    mut request: HttpRequest,
+) -> Result<HttpRequest, HttpRequestError> {
+    if !options.decode_requests {
+        return Ok(request);
+    }
+
+    // codings are listed in the order they were applied, identity being no coding at all
+    let encodings = request
+        .headers
+        .get_list("content-encoding")
+        .filter(|coding| !coding.eq_ignore_ascii_case("identity"))
+        .map(|coding| {
+            Encoding::from_str(coding)
+                .map_err(|_| HttpRequestError::UnsupportedContentCoding(coding.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if encodings.is_empty() {
+        return Ok(request);
+    }
+
+    let mut body = std::mem::take(&mut request.body).read_to_end().await?;
+    let limit = body
+        .len()
+        .saturating_mul(options.max_decode_ratio)
+        .min(options.max_decoded_size);
+
+    // decompressing up to the limit takes long enough to hold up other connections' tasks
+    let decoded = tokio::task::spawn_blocking(move || -> Result<_, HttpRequestError> {
+        for encoding in encodings.into_iter().rev() {
+            body = encoding.decompress(&body, limit)?.into();
+        }
+        Ok(body)
+    });
+    request.body = match decoded.await {
+        Ok(body) => RequestBody::Full(body?),
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    };
+    request.headers.remove("content-encoding");
+
+    Ok(request)
+}
+
+/// Compresses a handler's response with the coding the client prefers. Only successful
+/// responses with compressible content at least `min_size` long are compressed, unless the
+/// client refuses unencoded content, in which case anything is compressed, and the response
+/// replaced with a 406 if compression is disabled. Partial content is never compressed, as its
+/// ranges refer to the unencoded content.
+///
+/// Content in memory is compressed right away, and other content as it's written, so the
+/// length of the result isn't known in advance.
+pub fn encode_response(
+    options: &CompressionOptions,
+    accept: &AcceptEncoding,
+    response: HttpResponse,
+) -> HttpResponse {
+    let successful = (200..300).contains(&response.status_code) && response.status_code != 206;
+    if !successful || response.content.is_empty() || response.header("content-encoding").is_some() {
+        return response;
+    }
+
+    let mut response = response.with_vary("accept-encoding");
+    let content_type = response.header("content-type").unwrap_or_default();
+    let encoding = match coding(options, accept, content_type, response.content.len()) {
+        Some(encoding) => encoding,
+        None if accept.accepts_identity() => return response,
+        None => {
+            return HttpResponse::error(
+                406,
+                "Not Acceptable",
+                "no acceptable content coding".to_string(),
+            )
+            .with_vary("accept-encoding")
+        }
+    };
+
+    response.content = match std::mem::replace(&mut response.content, Body::Full(vec![])) {
+        Body::Full(content) => match encoding.compress(&content, options.level) {
+            Ok(compressed) => Body::Full(compressed),
+            Err(err) => {
+                log::error!(
+                    "Error compressing response with {}: {}",
+                    encoding.name(),
+                    err
+                );
+                return HttpResponse::status(500, "Internal Server Error");
+            }
+        },
+        content => compress_stream(encoding, options.level, content),
+    };
+
+    tag_coding(&mut response, encoding);
+    response.with_header("content-encoding", encoding.name())
+}
+
+/// Gives a 304 response the entity tag and `Vary` field of the 200 one it stands for, which
+/// would have had content of `content_type` and `len` bytes, compressed as `encode_response`
+/// would have (RFC 9110 section 15.4.5).
+pub fn encode_not_modified(
+    options: &CompressionOptions,
+    accept: &AcceptEncoding,
+    content_type: &str,
+    len: u64,
+    response: HttpResponse,
+) -> HttpResponse {
+    if len == 0 {
+        return response;
+    }
+
+    let mut response = response.with_vary("accept-encoding");
+    if let Some(encoding) = coding(options, accept, content_type, Some(len)) {
+        tag_coding(&mut response, encoding);
+    }
+    response
+}
+
+/// The coding successful content of `content_type` and `len` bytes, if known, is compressed
+/// with, if any.
+fn coding(
+    options: &CompressionOptions,
+    accept: &AcceptEncoding,
+    content_type: &str,
+    len: Option<u64>,
+) -> Option<Encoding> {
+    let long_enough = len.is_none_or(|len| len >= options.min_size as u64);
+    let compressible = long_enough && is_compressible(content_type);
+
+    accept
+        .preferred()
+        .filter(|_| options.enabled && (compressible || !accept.accepts_identity()))
+}
+
+/// The compressed content is a different representation, with its own entity tag: that of
+/// the unencoded content, with the name of the coding appended.
+fn tag_coding(response: &mut HttpResponse, encoding: Encoding) {
+    if let Some((_, etag)) = response
+        .headers
+        .iter_mut()
+        .find(|(key, _)| key.eq_ignore_ascii_case("etag"))
+    {
+        if let Some(opaque) = etag.strip_suffix('"') {
+            *etag = format!("{}-{}\"", opaque, encoding.name());
+        }
+    }
+}
+
+/// Compresses content as it's written. It's read through a pipe, a chunk at a time, by a task
+/// that sends on whatever has been compressed of it so far.
+fn compress_stream(encoding: Encoding, level: u32, content: Body) -> Body {
+    body::stream(None, move |sender| async move {
+        let (mut input, mut output) = tokio::io::duplex(body::CHUNK_SIZE);
+        let writing = tokio::spawn(async move { content.write_to(&mut input, false).await });
+
+        let mut encoder = Encoder::new(encoding, level);
+        let mut buffer = vec![0; body::CHUNK_SIZE];
+        loop {
+            let read = output.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            let compressed = encoder.write(&buffer[..read])?;
+            if !compressed.is_empty() {
+                sender.send(compressed.into()).await?;
+            }
+        }
+
+        // the pipe also ends if writing to it failed
+        writing.await.map_err(io::Error::other)??;
+        sender.send(encoder.finish()?.into()).await
+    })
+}
+
+/// Whether content of this media type is likely to get noticeably smaller when compressed.
+/// Most image, audio and video formats are compressed already.
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap().trim();
+    let media_type = media_type.to_ascii_lowercase();
+
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/bmp"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{listener::request::RequestReader, options::ServerOptions};
+
+    fn decompress(encoding: Encoding, compressed: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        match encoding {
+            Encoding::Gzip => GzDecoder::new(compressed).read_to_end(&mut output),
+            Encoding::Deflate => ZlibDecoder::new(compressed).read_to_end(&mut output),
+            Encoding::Brotli => {
+                brotli::Decompressor::new(compressed, 4096).read_to_end(&mut output)
+            }
+        }
+        .unwrap();
+        output
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let content = "hello compression ".repeat(100);
+
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            for level in [0, 6, 9] {
+                let compressed = encoding.compress(content.as_bytes(), level).unwrap();
+                if level > 0 {
+                    assert!(compressed.len() < content.len(), "{:?}", encoding);
+                }
+                assert_eq!(decompress(encoding, &compressed), content.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn compress_incompressible_content() {
+        // larger than the encoders' output buffers, and left about as large by compressing
+        let mut state = 1u32;
+        let content: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+
+        for encoding in ENCODINGS {
+            let mut encoder = Encoder::new(encoding, 6);
+            let mut compressed = Vec::new();
+            for piece in content.chunks(30_000) {
+                compressed.extend(encoder.write(piece).unwrap());
+            }
+            compressed.extend(encoder.finish().unwrap());
+            assert!(
+                decompress(encoding, &compressed) == content,
+                "{:?}",
+                encoding
+            );
+        }
+    }
+
+    #[test]
+    fn compress_empty() {
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let compressed = encoding.compress(b"", 6).unwrap();
+            assert_eq!(decompress(encoding, &compressed), b"");
+        }
+    }
+
+    fn accept(value: &str) -> AcceptEncoding {
+        AcceptEncoding::parse(value.split(',').map(str::trim).filter(|e| !e.is_empty()))
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let cases = [
+            ("", None, true),
+            ("gzip", Some(Encoding::Gzip), true),
+            ("invalid-1, gzip, invalid-2", Some(Encoding::Gzip), true),
+            ("gzip;q=0", None, true),
+            ("gzip, br", Some(Encoding::Brotli), true),
+            ("gzip;q=1, br;q=0.5", Some(Encoding::Gzip), true),
+            ("deflate;q=0.3, gzip;q=0.300", Some(Encoding::Gzip), true),
+            ("*", Some(Encoding::Brotli), true),
+            ("*;q=0.5, br;q=0", Some(Encoding::Gzip), true),
+            ("gzip;q=0.5, identity", None, true),
+            ("gzip;q=0.5, identity;q=0.5", Some(Encoding::Gzip), true),
+            ("identity;q=0", None, false),
+            ("*;q=0", None, false),
+            ("*;q=0, identity", None, true),
+            ("GZIP; Q=0.8", Some(Encoding::Gzip), true),
+            ("gzip;q=2, deflate;q=0.5000", None, true),
+        ];
+
+        for (value, preferred, identity) in cases {
+            let accept = accept(value);
+            assert_eq!(accept.preferred(), preferred, "{:?}", value);
+            assert_eq!(accept.accepts_identity(), identity, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn encode_compressible_responses() {
+        let options = CompressionOptions {
+            min_size: 4,
+            ..Default::default()
+        };
+        let encode = |value: &str, response| encode_response(&options, &accept(value), response);
+
+        let response = encode("gzip", HttpResponse::ok("text/html", b"<p>hi</p>".to_vec()));
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.header("vary"), Some("accept-encoding"));
+
+        let response = encode("gzip", HttpResponse::ok("text/plain", b"hi".to_vec()));
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.header("vary"), Some("accept-encoding"));
+
+        let response = encode("gzip", HttpResponse::ok("image/png", b"\x89PNG".to_vec()));
+        assert_eq!(response.header("content-encoding"), None);
+
+        let response = encode("gzip", HttpResponse::status(404, "Not Found"));
+        assert_eq!(response.header("vary"), None);
+
+        let response =
+            HttpResponse::ok("text/plain", b"hello".to_vec()).with_header("etag", "\"1\"");
+        let response = encode("br", response);
+        assert_eq!(response.header("etag"), Some("\"1-br\""));
+
+        let response = HttpResponse {
+            status_code: 206,
+            ..HttpResponse::ok("text/plain", b"hello".to_vec())
+        };
+        let response = encode("gzip", response);
+        assert_eq!(response.header("content-encoding"), None);
+    }
+
+    #[test]
+    fn encode_when_identity_refused() {
+        let options = CompressionOptions::default();
+        let response = HttpResponse::ok("image/png", b"\x89PNG".to_vec());
+        let response = encode_response(&options, &accept("gzip, identity;q=0"), response);
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+
+        let response = HttpResponse::ok("text/plain", b"hello".to_vec());
+        let response = encode_response(&options, &accept("compress, identity;q=0"), response);
+        assert_eq!(response.status_code, 406);
+        assert_eq!(response.header("vary"), Some("accept-encoding"));
+    }
+
+    #[tokio::test]
+    async fn encode_streamed_content() {
+        let content: Vec<u8> = (0..200_000).map(|i| b"abcdefgh"[i % 8]).collect();
+        let expected = content.clone();
+
+        for encoding in ENCODINGS {
+            let content = content.clone();
+            let streamed = body::stream(Some(content.len() as u64), |sender| async move {
+                for chunk in content.chunks(10_000) {
+                    sender.send(chunk.to_vec().into()).await?;
+                }
+                Ok(())
+            });
+            let response = HttpResponse {
+                content: streamed,
+                ..HttpResponse::ok("text/plain", vec![])
+            }
+            .with_header("etag", "\"1\"");
+
+            let options = CompressionOptions::default();
+            let response = encode_response(&options, &accept(encoding.name()), response);
+            assert_eq!(response.header("content-encoding"), Some(encoding.name()));
+            assert_eq!(
+                response.header("etag"),
+                Some(format!("\"1-{}\"", encoding.name()).as_str())
+            );
+            assert_eq!(response.content.len(), None);
+
+            let compressed = response.content.collect().await.unwrap();
+            assert!(compressed.len() < expected.len() / 10);
+            let decompressed = encoding.decompress(&compressed, expected.len()).unwrap();
+            assert!(decompressed == expected, "{:?}", encoding);
+        }
+    }
+
+    async fn request(encoding: &str, body: &[u8]) -> HttpRequest {
+        let head = format!(
+            "POST /files/upload HTTP/1.1\r\ncontent-encoding: {}\r\ncontent-length: {}\r\n\r\n",
+            encoding,
+            body.len()
+        );
+        let input = [head.as_bytes(), body].concat();
+        let options = ServerOptions::default();
+        let mut reader = RequestReader::new(&input[..], options.limits, options.timeouts);
+        reader.read().await.unwrap().unwrap()
+    }
+
+    fn decoding() -> CompressionOptions {
+        CompressionOptions {
+            decode_requests: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_request_bodies() {
+        let content = b"hello hello hello hello hello hello";
+
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let body = encoding.compress(content, 6).unwrap();
+            let request = request(encoding.name(), &body).await;
+            let request = decode_request(&decoding(), request).await.unwrap();
+            assert_eq!(request.body, &content[..]);
+            assert_eq!(request.headers.get("content-encoding"), None);
+        }
+
+        // applied first gzip, then brotli
+        let body = Encoding::Gzip.compress(content, 6).unwrap();
+        let body = Encoding::Brotli.compress(&body, 6).unwrap();
+        let request = request("gzip, identity, br", &body).await;
+        let request = decode_request(&decoding(), request).await.unwrap();
+        assert_eq!(request.body, &content[..]);
+    }
+
+    #[tokio::test]
+    async fn keep_bodies_when_decoding_disabled() {
+        let body = Encoding::Gzip.compress(b"hello", 6).unwrap();
+        let request = request("gzip", &body).await;
+        let request = decode_request(&CompressionOptions::default(), request)
+            .await
+            .unwrap();
+        assert_eq!(request.body, body);
+        assert_eq!(request.headers.get("content-encoding"), Some(&b"gzip"[..]));
+    }
+
+    #[tokio::test]
+    async fn reject_undecodable_bodies() {
+        let err = decode_request(&decoding(), request("compress", b"data").await)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), 415);
+        assert_eq!(
+            err.to_response().header("accept-encoding"),
+            Some(SUPPORTED_CODINGS)
+        );
+
+        let err = decode_request(&decoding(), request("gzip", b"not gzip").await)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn reject_decompression_bombs() {
+        let zeros = vec![0; 1 << 20];
+        let body = Encoding::Gzip.compress(&zeros, 9).unwrap();
+
+        // over the ratio limit
+        let options = decoding();
+        let err = decode_request(&options, request("gzip", &body).await)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HttpRequestError::DecodedPayloadTooLarge));
+
+        // over the size limit
+        let options = CompressionOptions {
+            max_decode_ratio: 10_000,
+            max_decoded_size: (1 << 20) - 1,
+            ..decoding()
+        };
+        let err = decode_request(&options, request("gzip", &body).await)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HttpRequestError::DecodedPayloadTooLarge));
+
+        // exactly at the size limit
+        let options = CompressionOptions {
+            max_decoded_size: 1 << 20,
+            ..options
+        };
+        let request = decode_request(&options, request("gzip", &body).await)
+            .await
+            .unwrap();
+        assert_eq!(request.body, zeros);
+    }
+
+    #[test]
+    fn parse_names() {
+        assert_eq!("GZIP".parse(), Ok(Encoding::Gzip));
+        assert_eq!("br".parse(), Ok(Encoding::Brotli));
+        assert_eq!("compress".parse::<Encoding>(), Err(()));
+    }
+}
@@ -0,0 +1,429 @@
+//! Validators for files and conditional requests that use them (RFC 9110 section 13).
+
+use crate::listener::{compression::ENCODINGS, headers::Headers, HttpResponse};
+use std::{
+    fs::Metadata,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What identifies the current version of a file, so clients can tell whether what they
+/// have is still up to date.
+pub struct Validators {
+    etag: EntityTag,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, PartialEq)]
+struct EntityTag {
+    weak: bool,
+    opaque: String,
+}
+
+impl Validators {
+    /// Derives validators from the size and modification time of a file. The entity tag is
+    /// weak if the file was modified within the last second, as it may still be changing
+    /// without its modification time doing so.
+    pub fn of(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let since_epoch = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let recent = match modified {
+            Some(modified) => SystemTime::now()
+                .duration_since(modified)
+                .map(|age| age < Duration::from_secs(1))
+                .unwrap_or(true),
+            None => true,
+        };
+
+        Self {
+            etag: EntityTag {
+                weak: recent,
+                opaque: format!("{:x}-{:x}", metadata.len(), since_epoch.as_nanos()),
+            },
+            modified,
+        }
+    }
+
+    /// Evaluates the preconditions of a GET request in the order RFC 9110 section 13.2.2
+    /// gives. Returns a 412 response if a precondition for the request failed, or a 304 one if
+    /// the client's copy of the file is up to date, and `None` to go on with the request.
+    pub fn evaluate(&self, headers: &Headers) -> Option<HttpResponse> {
+        if let Some(if_match) = header(headers, "if-match") {
+            if !self.matches_any(&if_match, false) {
+                return Some(precondition_failed());
+            }
+        } else if let Some(date) =
+            header(headers, "if-unmodified-since").and_then(|d| parse_date(&d))
+        {
+            if self.modified_since(date) {
+                return Some(precondition_failed());
+            }
+        }
+
+        if let Some(if_none_match) = header(headers, "if-none-match") {
+            if self.matches_any(&if_none_match, true) {
+                return Some(self.not_modified());
+            }
+        } else if let Some(date) = header(headers, "if-modified-since").and_then(|d| parse_date(&d))
+        {
+            if !self.modified_since(date) {
+                return Some(self.not_modified());
+            }
+        }
+
+        None
+    }
+
+    /// Whether a range request may be served, given its `If-Range` field (RFC 9110 section
+    /// 13.1.5). An entity tag has to match strongly, and a date exactly.
+    pub fn matches_if_range(&self, if_range: Option<&[u8]>) -> bool {
+        let if_range = match if_range.map(std::str::from_utf8) {
+            None => return true,
+            Some(Ok(if_range)) => if_range.trim(),
+            Some(Err(_)) => return false,
+        };
+
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // the ranges are of the unencoded content, so tags of compressed versions of the
+            // file don't match
+            return match parse_entity_tags(if_range).as_deref() {
+                Some([etag]) => !etag.weak && !self.etag.weak && etag.opaque == self.etag.opaque,
+                _ => false,
+            };
+        }
+
+        match (parse_date(if_range), self.modified) {
+            (Some(date), Some(modified)) => date == truncate_to_seconds(modified),
+            _ => false,
+        }
+    }
+
+    /// Adds the `ETag` and `Last-Modified` fields to a response.
+    pub fn add_to(&self, response: HttpResponse) -> HttpResponse {
+        let response = response.with_header("etag", &self.etag());
+        match self.last_modified() {
+            Some(last_modified) => response.with_header("last-modified", &last_modified),
+            None => response,
+        }
+    }
+
+    /// The entity tag, as it appears in an `ETag` field.
+    pub fn etag(&self) -> String {
+        match self.etag.weak {
+            true => format!("W/\"{}\"", self.etag.opaque),
+            false => format!("\"{}\"", self.etag.opaque),
+        }
+    }
+
+    /// The modification time as an HTTP date, if the file system has one.
+    pub fn last_modified(&self) -> Option<String> {
+        self.modified.map(httpdate::fmt_http_date)
+    }
+
+    /// Whether `etag` matches the file's entity tag in the weak comparison.
+    pub fn matches_etag(&self, etag: &str) -> bool {
+        match parse_entity_tags(etag).as_deref() {
+            Some([etag]) => self.matches(etag, true),
+            _ => false,
+        }
+    }
+
+    /// Whether a list of entity tags, or `*`, matches the file.
+    fn matches_any(&self, value: &str, weak: bool) -> bool {
+        if value.trim() == "*" {
+            return true;
+        }
+
+        parse_entity_tags(value)
+            .unwrap_or_default()
+            .iter()
+            .any(|etag| self.matches(etag, weak))
+    }
+
+    /// Compares `etag` to the file's entity tag weakly or strongly. Tags of compressed versions
+    /// of the file, which have the name of their coding appended, match too, so a client that
+    /// cached one can still revalidate it or make a change conditional on it.
+    fn matches(&self, etag: &EntityTag, weak: bool) -> bool {
+        let opaque = ENCODINGS
+            .iter()
+            .find_map(|encoding| {
+                let suffix = format!("-{}", encoding.name());
+                etag.opaque.strip_suffix(suffix.as_str())
+            })
+            .unwrap_or(&etag.opaque);
+        opaque == self.etag.opaque && (weak || !etag.weak && !self.etag.weak)
+    }
+
+    /// Whether the file was modified after `date`, to the second.
+    fn modified_since(&self, date: SystemTime) -> bool {
+        match self.modified {
+            Some(modified) => truncate_to_seconds(modified) > date,
+            None => true,
+        }
+    }
+
+    fn not_modified(&self) -> HttpResponse {
+        self.add_to(HttpResponse::status(304, "Not Modified"))
+    }
+}
+
+/// Evaluates the preconditions of a request that changes or removes a file, given the file's
+/// current validators or `None` if it doesn't exist, in the order RFC 9110 section 13.2.2
+/// gives. Returns a 412 response if a precondition failed, and `None` to go on with the
+/// request. Clients use `If-Match` to avoid overwriting changes made since they last read the
+/// file, and `If-None-Match: *` to avoid overwriting a file they didn't know existed.
+pub fn evaluate_change(current: Option<&Validators>, headers: &Headers) -> Option<HttpResponse> {
+    if let Some(if_match) = header(headers, "if-match") {
+        if !current.is_some_and(|current| current.matches_any(&if_match, false)) {
+            return Some(precondition_failed());
+        }
+    } else if let Some(date) = header(headers, "if-unmodified-since").and_then(|d| parse_date(&d)) {
+        if current.is_some_and(|current| current.modified_since(date)) {
+            return Some(precondition_failed());
+        }
+    }
+
+    if let Some(if_none_match) = header(headers, "if-none-match") {
+        if current.is_some_and(|current| current.matches_any(&if_none_match, true)) {
+            return Some(precondition_failed());
+        }
+    }
+
+    None
+}
+
+pub(super) fn precondition_failed() -> HttpResponse {
+    HttpResponse::error(
+        412,
+        "Precondition Failed",
+        "precondition failed".to_string(),
+    )
+}
+
+/// A header field's value, with all of its lines combined.
+fn header(headers: &Headers, name: &str) -> Option<String> {
+    let lines: Vec<_> = headers
+        .get_all(name)
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(", "))
+}
+
+/// Parses a comma-separated list of entity tags. Returns `None` if it's malformed.
+fn parse_entity_tags(value: &str) -> Option<Vec<EntityTag>> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return Some(tags);
+        }
+
+        let weak = rest.starts_with("W/");
+        if weak {
+            rest = &rest[2..];
+        }
+
+        // the opaque tag can contain commas, but not quotes
+        let quoted = rest.strip_prefix('"')?;
+        let end = quoted.find('"')?;
+        tags.push(EntityTag {
+            weak,
+            opaque: quoted[..end].to_string(),
+        });
+        rest = &quoted[end + 1..];
+    }
+}
+
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+/// HTTP dates only have a resolution of one second.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn validators(weak: bool) -> Validators {
+        Validators {
+            etag: EntityTag {
+                weak,
+                opaque: "a-1".to_string(),
+            },
+            modified: Some(UNIX_EPOCH + Duration::from_millis(1_000_000_000_500)),
+        }
+    }
+
+    fn evaluate(validators: &Validators, fields: &[(&'static str, &'static str)]) -> u16 {
+        let fields = fields
+            .iter()
+            .map(|(name, value)| (Bytes::from(*name), Bytes::from(*value)))
+            .collect();
+        validators
+            .evaluate(&Headers::new(fields))
+            .map_or(200, |response| response.status_code)
+    }
+
+    const MODIFIED: &str = "Sun, 09 Sep 2001 01:46:40 GMT";
+    const BEFORE: &str = "Sun, 09 Sep 2001 01:46:39 GMT";
+    const AFTER: &str = "Sun, 09 Sep 2001 01:46:41 GMT";
+
+    #[test]
+    fn parse_entity_tag_lists() {
+        let tags = parse_entity_tags(r#""a", W/"b,c" ,"""#).unwrap();
+        let opaque: Vec<_> = tags.iter().map(|t| (t.weak, t.opaque.as_str())).collect();
+        assert_eq!(opaque, [(false, "a"), (true, "b,c"), (false, "")]);
+
+        assert_eq!(parse_entity_tags("a"), None);
+        assert_eq!(parse_entity_tags(r#""a"#), None);
+        assert_eq!(parse_entity_tags(r#""a" b"#), None);
+    }
+
+    #[test]
+    fn evaluate_if_none_match() {
+        let strong = validators(false);
+        let cases = [
+            (r#""a-1""#, 304),
+            (r#"W/"a-1""#, 304),
+            (r#""x", "a-1""#, 304),
+            (r#""a-1-gzip""#, 304),
+            ("*", 304),
+            (r#""x""#, 200),
+            ("garbage", 200),
+        ];
+
+        for (value, status) in cases {
+            assert_eq!(
+                evaluate(&strong, &[("if-none-match", value)]),
+                status,
+                "{}",
+                value
+            );
+        }
+
+        // takes precedence over If-Modified-Since
+        let fields = [("if-none-match", r#""x""#), ("if-modified-since", AFTER)];
+        assert_eq!(evaluate(&strong, &fields), 200);
+    }
+
+    #[test]
+    fn evaluate_if_match() {
+        let cases = [
+            (false, r#""a-1""#, 200),
+            (false, r#""x", "a-1""#, 200),
+            (false, "*", 200),
+            (false, r#"W/"a-1""#, 412),
+            (false, r#""a-1-gzip""#, 200),
+            (false, r#"W/"a-1-gzip""#, 412),
+            (false, r#""x""#, 412),
+            (true, r#""a-1""#, 412),
+            (true, r#"W/"a-1""#, 412),
+        ];
+
+        for (weak, value, status) in cases {
+            let validators = validators(weak);
+            assert_eq!(
+                evaluate(&validators, &[("if-match", value)]),
+                status,
+                "{}",
+                value
+            );
+        }
+
+        // takes precedence over If-Unmodified-Since
+        let fields = [("if-match", r#""a-1""#), ("if-unmodified-since", BEFORE)];
+        assert_eq!(evaluate(&validators(false), &fields), 200);
+    }
+
+    #[test]
+    fn evaluate_dates() {
+        let validators = validators(false);
+        let cases = [
+            ("if-modified-since", MODIFIED, 304),
+            ("if-modified-since", AFTER, 304),
+            ("if-modified-since", BEFORE, 200),
+            ("if-modified-since", "yesterday", 200),
+            ("if-unmodified-since", MODIFIED, 200),
+            ("if-unmodified-since", AFTER, 200),
+            ("if-unmodified-since", BEFORE, 412),
+            ("if-unmodified-since", "yesterday", 200),
+        ];
+
+        for (name, value, status) in cases {
+            assert_eq!(
+                evaluate(&validators, &[(name, value)]),
+                status,
+                "{} {}",
+                name,
+                value
+            );
+        }
+
+        // a failed If-Unmodified-Since is reported before a matching If-None-Match
+        let fields = [("if-unmodified-since", BEFORE), ("if-none-match", "*")];
+        assert_eq!(evaluate(&validators, &fields), 412);
+    }
+
+    #[test]
+    fn evaluate_change_preconditions() {
+        let fields = |fields: &[(&'static str, &'static str)]| {
+            Headers::new(
+                fields
+                    .iter()
+                    .map(|(name, value)| (Bytes::from(*name), Bytes::from(*value)))
+                    .collect(),
+            )
+        };
+        let strong = validators(false);
+        let cases = [
+            (Some(&strong), ("if-match", r#""a-1""#), 200),
+            (Some(&strong), ("if-match", "*"), 200),
+            (Some(&strong), ("if-match", r#""a-1-br""#), 200),
+            (Some(&strong), ("if-match", r#""x""#), 412),
+            (None, ("if-match", "*"), 412),
+            (None, ("if-match", r#""a-1""#), 412),
+            (Some(&strong), ("if-none-match", "*"), 412),
+            (Some(&strong), ("if-none-match", r#"W/"a-1""#), 412),
+            (Some(&strong), ("if-none-match", r#""x""#), 200),
+            (None, ("if-none-match", "*"), 200),
+            (Some(&strong), ("if-unmodified-since", BEFORE), 412),
+            (Some(&strong), ("if-unmodified-since", MODIFIED), 200),
+            (None, ("if-unmodified-since", BEFORE), 200),
+            (Some(&strong), ("if-modified-since", AFTER), 200),
+        ];
+
+        for (current, field, status) in cases {
+            let response = evaluate_change(current, &fields(&[field]));
+            assert_eq!(
+                response.map_or(200, |response| response.status_code),
+                status,
+                "{:?} {:?}",
+                current.is_some(),
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn check_if_range() {
+        let strong = validators(false);
+        assert!(strong.matches_if_range(None));
+        assert!(strong.matches_if_range(Some(br#""a-1""#)));
+        assert!(strong.matches_if_range(Some(MODIFIED.as_bytes())));
+        assert!(!strong.matches_if_range(Some(br#"W/"a-1""#)));
+        assert!(!strong.matches_if_range(Some(br#""a-1-gzip""#)));
+        assert!(!strong.matches_if_range(Some(br#""a-1", "b""#)));
+        assert!(!strong.matches_if_range(Some(AFTER.as_bytes())));
+
+        let weak = validators(true);
+        assert!(!weak.matches_if_range(Some(br#""a-1""#)));
+    }
+}
@@ -0,0 +1,396 @@
+//! Serving files from the root directory.
+
+use crate::{
+    listener::{
+        body::{self, Body},
+        compression::{self, AcceptEncoding},
+        conditional::Validators,
+        listing, mime,
+        range::{self, Multipart, Ranges},
+        request::HttpRequest,
+        HttpResponse,
+    },
+    log,
+    options::{CompressionOptions, FileOptions},
+};
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// Files, or ranges of them, up to this long are read into memory, where they can be
+/// compressed. Longer ones are read as they're written.
+const MAX_BUFFERED_LEN: u64 = 1024 * 1024;
+
+/// Responds to a GET for the file at `path`, or for the parts of it the request's `Range`
+/// field asks for. Conditional requests are answered with 304 or 412 as appropriate, a 304
+/// with the entity tag the content would have had once compressed. Directories are listed if
+/// listings are enabled.
+pub async fn get(
+    request: &HttpRequest,
+    path: &Path,
+    options: &FileOptions,
+    compression: &CompressionOptions,
+) -> HttpResponse {
+    match serve(request, path, options, compression).await {
+        Ok(response) => response,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => HttpResponse::status(404, "Not Found"),
+        Err(err) => {
+            log::error!("Error reading contents of file {:?}: {}", path, err);
+            HttpResponse::status(500, "Internal Server Error")
+        }
+    }
+}
+
+async fn serve(
+    request: &HttpRequest,
+    path: &Path,
+    options: &FileOptions,
+    compression: &CompressionOptions,
+) -> io::Result<HttpResponse> {
+    let mut file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if metadata.is_dir() && options.listings {
+        return listing::get(request, path, options).await;
+    }
+    if !metadata.is_file() {
+        return Ok(HttpResponse::status(404, "Not Found"));
+    }
+
+    let len = metadata.len();
+    let content_type = match mime::from_extension(path, &options.mime_types) {
+        Some(content_type) => content_type,
+        None if options.sniff => sniff(&mut file).await?,
+        None => mime::DEFAULT_TYPE.to_string(),
+    };
+    let content_type = content_type.as_str();
+
+    let validators = Validators::of(&metadata);
+    match validators.evaluate(&request.headers) {
+        Some(response) if response.status_code == 304 => {
+            let accept = AcceptEncoding::from_headers(&request.headers);
+            let response =
+                compression::encode_not_modified(compression, &accept, content_type, len, response);
+            return Ok(response);
+        }
+        Some(response) => return Ok(response),
+        None => {}
+    }
+
+    let ranges = match request.headers.get("range") {
+        Some(range) if validators.matches_if_range(request.headers.get("if-range")) => {
+            std::str::from_utf8(range).map_or(Ranges::Full, |range| range::parse(range, len))
+        }
+        _ => Ranges::Full,
+    };
+
+    let response = match ranges {
+        Ranges::Full => HttpResponse {
+            content: content(file, 0..len).await?,
+            ..HttpResponse::status(200, "OK")
+        }
+        .with_header("content-type", content_type)
+        .with_header("accept-ranges", "bytes"),
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0].clone();
+            let content_range = range::content_range(&range, len);
+            HttpResponse {
+                content: content(file, range).await?,
+                ..HttpResponse::status(206, "Partial Content")
+            }
+            .with_header("content-type", content_type)
+            .with_header("content-range", &content_range)
+        }
+        Ranges::Partial(ranges) => {
+            let multipart = Multipart::new(content_type, len, &ranges);
+            let multipart_type = multipart.content_type.clone();
+            let content = body::stream(Some(multipart.len(&ranges)), move |sender| async move {
+                for (head, range) in multipart.heads.into_iter().zip(ranges) {
+                    sender.send(head.into()).await?;
+                    sender.send_file(&mut file, range).await?;
+                }
+                sender.send(multipart.tail.into()).await
+            });
+
+            HttpResponse {
+                content,
+                ..HttpResponse::status(206, "Partial Content")
+            }
+            .with_header("content-type", &multipart_type)
+        }
+        Ranges::Unsatisfiable => HttpResponse::error(
+            416,
+            "Range Not Satisfiable",
+            "requested range not satisfiable".to_string(),
+        )
+        .with_header("content-range", &format!("bytes */{}", len)),
+    };
+
+    Ok(validators
+        .add_to(response)
+        .with_header("x-content-type-options", "nosniff"))
+}
+
+/// Guesses the type of a file from its first bytes, leaving it positioned at the start.
+async fn sniff(file: &mut File) -> io::Result<String> {
+    let mut start = Vec::with_capacity(mime::SNIFF_LEN);
+    (&mut *file)
+        .take(mime::SNIFF_LEN as u64)
+        .read_to_end(&mut start)
+        .await?;
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(mime::sniff(&start))
+}
+
+/// The content of a range of a file, read now if it's short enough and when it's written if
+/// not.
+async fn content(mut file: File, range: Range<u64>) -> io::Result<Body> {
+    if range.end - range.start > MAX_BUFFERED_LEN {
+        return Ok(Body::File { file, range });
+    }
+
+    let mut data = vec![0; (range.end - range.start) as usize];
+    file.seek(SeekFrom::Start(range.start)).await?;
+    file.read_exact(&mut data).await?;
+    Ok(Body::Full(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        listener::{request::RequestReader, testing::TempDir},
+        options::ServerOptions,
+    };
+    use std::path::PathBuf;
+
+    /// Writes a file last modified a minute ago, so its validators are strong.
+    fn old_file(dir: &TempDir, name: &str, content: &[u8]) -> PathBuf {
+        let path = dir.write(name, content);
+        let modified = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        path
+    }
+
+    async fn get_with(path: &Path, headers: &str) -> HttpResponse {
+        get_with_options(path, headers, &FileOptions::default()).await
+    }
+
+    async fn request(headers: &str) -> HttpRequest {
+        let input = format!("GET /files/file HTTP/1.1\r\n{}\r\n", headers);
+        let options = ServerOptions::default();
+        let mut reader = RequestReader::new(input.as_bytes(), options.limits, options.timeouts);
+        reader.read().await.unwrap().unwrap()
+    }
+
+    /// Gets a file, with its content read into memory.
+    async fn get_with_options(path: &Path, headers: &str, files: &FileOptions) -> HttpResponse {
+        let response = get(&request(headers).await, path, files, &Default::default()).await;
+        HttpResponse {
+            content: Body::Full(response.content.collect().await.unwrap()),
+            ..response
+        }
+    }
+
+    #[tokio::test]
+    async fn get_whole_file() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "whole", b"0123456789");
+        let response = get_with(&file, "").await;
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.content, b"0123456789");
+        assert_eq!(response.header("accept-ranges"), Some("bytes"));
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(response.header("x-content-type-options"), Some("nosniff"));
+        assert!(response.header("etag").is_some());
+        assert!(response.header("last-modified").is_some());
+
+        let response = get_with(Path::new("/does/not/exist"), "").await;
+        assert_eq!(response.status_code, 404);
+        let response = get_with(&std::env::temp_dir(), "").await;
+        assert_eq!(response.status_code, 404);
+    }
+
+    #[tokio::test]
+    async fn get_single_range() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "single", b"0123456789");
+        let response = get_with(&file, "range: bytes=2-4\r\n").await;
+
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.content, b"234");
+        assert_eq!(response.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(response.header("accept-ranges"), None);
+    }
+
+    #[tokio::test]
+    async fn get_multiple_ranges() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "multiple", b"0123456789");
+        let response = get_with(&file, "range: bytes=0-1, -2\r\n").await;
+
+        assert_eq!(response.status_code, 206);
+        let content_type = response.header("content-type").unwrap();
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let body = String::from_utf8(response.content.bytes().unwrap().to_vec()).unwrap();
+        assert!(body.contains("content-range: bytes 0-1/10\r\n\r\n01\r\n"));
+        assert!(body.contains("content-range: bytes 8-9/10\r\n\r\n89\r\n"));
+    }
+
+    #[tokio::test]
+    async fn get_if_changed() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "conditional", b"0123456789");
+        let response = get_with(&file, "").await;
+        let etag = response.header("etag").unwrap();
+        assert!(etag.starts_with('"'));
+
+        let response = get_with(&file, &format!("if-none-match: {}\r\n", etag)).await;
+        assert_eq!(response.status_code, 304);
+        assert_eq!(response.header("etag"), Some(etag));
+        assert!(response.content.is_empty());
+
+        let response = get_with(&file, "if-match: \"other\"\r\n").await;
+        assert_eq!(response.status_code, 412);
+
+        // preconditions are evaluated before the range
+        let headers = format!("if-none-match: {}\r\nrange: bytes=0-0\r\n", etag);
+        let response = get_with(&file, &headers).await;
+        assert_eq!(response.status_code, 304);
+
+        std::fs::write(&file, b"changed").unwrap();
+        let response = get_with(&file, &format!("if-none-match: {}\r\n", etag)).await;
+        assert_eq!(response.status_code, 200);
+        assert!(response.header("etag").unwrap().starts_with("W/"));
+    }
+
+    #[tokio::test]
+    async fn reject_unsatisfiable_range() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "unsatisfiable", b"0123456789");
+        let response = get_with(&file, "range: bytes=10-\r\n").await;
+
+        assert_eq!(response.status_code, 416);
+        assert_eq!(response.header("content-range"), Some("bytes */10"));
+    }
+
+    #[tokio::test]
+    async fn stream_large_files() {
+        let content: Vec<u8> = (0..MAX_BUFFERED_LEN + 10).map(|i| i as u8).collect();
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "large", &content);
+        let options = FileOptions::default();
+
+        let response = get(&request("").await, &file, &options, &Default::default()).await;
+        assert!(matches!(response.content, Body::File { .. }));
+        let response = get(
+            &request("range: bytes=0-0, 5-\r\n").await,
+            &file,
+            &options,
+            &Default::default(),
+        )
+        .await;
+        assert!(matches!(response.content, Body::Stream { .. }));
+        let body = response.content.collect().await.unwrap();
+        // the data of the last range is followed by the closing boundary
+        let end = body.len() - "\r\n--0123456789abcdef--\r\n".len();
+        assert_eq!(&body[end - content.len() + 5..end], &content[5..]);
+
+        let response = get_with(&file, "").await;
+        assert_eq!(response.content.bytes(), Some(&content[..]));
+        let response = get_with(&file, "range: bytes=1-\r\n").await;
+        assert_eq!(response.content.bytes(), Some(&content[1..]));
+    }
+
+    #[tokio::test]
+    async fn check_if_range() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "if-range", b"0123456789");
+        let response = get_with(&file, "").await;
+        let etag = response.header("etag").unwrap();
+        let last_modified = response.header("last-modified").unwrap();
+
+        let matching = [
+            format!("if-range: {}\r\n", etag),
+            format!("if-range: {}\r\n", last_modified),
+        ];
+        for headers in matching {
+            let response = get_with(&file, &format!("range: bytes=0-0\r\n{}", headers)).await;
+            assert_eq!(response.status_code, 206, "{:?}", headers);
+        }
+
+        let stale = [
+            "if-range: \"other\"\r\n".to_string(),
+            format!("if-range: W/{}\r\n", etag),
+            "if-range: Thu, 01 Jan 1970 00:00:00 GMT\r\n".to_string(),
+        ];
+        for headers in stale {
+            let response = get_with(&file, &format!("range: bytes=0-0\r\n{}", headers)).await;
+            assert_eq!(response.status_code, 200, "{:?}", headers);
+            assert_eq!(response.content, b"0123456789");
+        }
+    }
+
+    #[tokio::test]
+    async fn detect_content_type() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "type.HTML", b"<p>hello</p>");
+        let response = get_with(&file, "").await;
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let mut options = FileOptions::default();
+        options
+            .mime_types
+            .insert("html".to_string(), "application/xhtml+xml".to_string());
+        let response = get_with_options(&file, "", &options).await;
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/xhtml+xml")
+        );
+
+        // multipart parts carry the file's type
+        let response = get_with(&file, "range: bytes=0-0, 2-2\r\n").await;
+        let body = String::from_utf8(response.content.bytes().unwrap().to_vec()).unwrap();
+        assert!(body.contains("content-type: text/html; charset=utf-8\r\n"));
+    }
+
+    #[tokio::test]
+    async fn sniff_extensionless_files() {
+        let dir = TempDir::new("files");
+        let file = old_file(&dir, "sniffed", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        let options = FileOptions {
+            sniff: true,
+            ..FileOptions::default()
+        };
+
+        let response = get_with_options(&file, "", &options).await;
+        assert_eq!(response.header("content-type"), Some("image/png"));
+        assert_eq!(response.content.len(), Some(16));
+        let response = get_with_options(&file, "range: bytes=1-3\r\n", &options).await;
+        assert_eq!(response.header("content-type"), Some("image/png"));
+        assert_eq!(response.content, b"PNG");
+
+        let response = get_with(&file, "").await;
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/octet-stream")
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use bytes::Bytes;
+
+/// Header fields of a request, kept as slices of the buffer they were read into. Names are
+/// compared case-insensitively. Values may contain bytes that aren't valid UTF-8, which the
+/// text-oriented accessors skip.
+#[derive(Debug, Default)]
+pub struct Headers(Vec<(Bytes, Bytes)>);
+
+impl Headers {
+    pub fn new(fields: Vec<(Bytes, Bytes)>) -> Self {
+        Self(fields)
+    }
+
+    /// Removes every line of the named field.
+    pub fn remove(&mut self, name: &str) {
+        self.0
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name.as_bytes()));
+    }
+
+    /// The first value of the named field.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, value)| value.as_ref())
+    }
+
+    /// All values of the named field, in the order they were received.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl DoubleEndedIterator<Item = &'a [u8]> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, value)| value.as_ref())
+    }
+
+    /// Elements of a comma-separated list field, combined across all its lines.
+    pub fn get_list<'a>(&'a self, name: &'a str) -> impl DoubleEndedIterator<Item = &'a str> {
+        self.get_all(name)
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','))
+            .map(|element| element.trim())
+            .filter(|element| !element.is_empty())
+    }
+}
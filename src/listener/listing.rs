@@ -0,0 +1,584 @@
+//! Listings of the contents of directories under the root directory, as HTML or JSON.
+
+use crate::{
+    listener::{compression::weighted, headers::Headers, path, request::HttpRequest, HttpResponse},
+    options::{FileOptions, SymlinkPolicy},
+};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    fmt::Write,
+    fs::Metadata,
+    io,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::fs;
+
+const DEFAULT_PER_PAGE: usize = 100;
+
+/// Most entries listed on one page, so a huge directory can't make for a huge response.
+const MAX_PER_PAGE: usize = 1000;
+
+/// How entries are ordered. Directories always come before files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+/// What a listing request asks for in its query string, with `sort`, `order`, `page` and
+/// `per_page` parameters.
+#[derive(Debug, PartialEq)]
+struct Query {
+    sort: SortKey,
+    descending: bool,
+    /// Starting from one.
+    page: usize,
+    per_page: usize,
+}
+
+impl Default for Query {
+    fn default() -> Self {
+        Self {
+            sort: SortKey::Name,
+            descending: false,
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+impl Query {
+    /// Parses a query string. Parameters other than those for listings are ignored, but an
+    /// invalid value for one of those is an error.
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let invalid = || format!("invalid value {:?} for {}", value, name);
+            match name {
+                "sort" => {
+                    parsed.sort = match value {
+                        "name" => SortKey::Name,
+                        "size" => SortKey::Size,
+                        "modified" => SortKey::Modified,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "order" => {
+                    parsed.descending = match value {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "page" => {
+                    parsed.page = value
+                        .parse()
+                        .ok()
+                        .filter(|&page| page > 0)
+                        .ok_or_else(invalid)?
+                }
+                "per_page" => {
+                    parsed.per_page = value
+                        .parse()
+                        .ok()
+                        .filter(|per_page| (1..=MAX_PER_PAGE).contains(per_page))
+                        .ok_or_else(invalid)?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// The query string for a page of this listing, sorted by `sort` in `descending` order.
+    fn href(&self, sort: SortKey, descending: bool, page: usize) -> String {
+        format!(
+            "?sort={}&order={}&page={}&per_page={}",
+            sort.name(),
+            if descending { "desc" } else { "asc" },
+            page,
+            self.per_page
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: Kind,
+    /// In bytes, for files only.
+    size: Option<u64>,
+    /// In seconds since the Unix epoch.
+    modified: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Directory,
+    File,
+}
+
+/// A page of a listing, as sent in JSON.
+#[derive(Serialize)]
+struct Listing<'a> {
+    path: &'a str,
+    page: usize,
+    per_page: usize,
+    total: usize,
+    entries: &'a [Entry],
+}
+
+/// Responds to a GET for the directory at `path` with a page of its entries, in JSON if the
+/// request's `Accept` field prefers it to HTML. Requests for a directory without a trailing
+/// slash are redirected to one with it, so the links in the listing are relative to it.
+pub async fn get(
+    request: &HttpRequest,
+    path: &Path,
+    options: &FileOptions,
+) -> io::Result<HttpResponse> {
+    let (target, query) = request
+        .target()
+        .split_once('?')
+        .unwrap_or((request.target(), ""));
+    if !target.ends_with('/') {
+        let location = match query {
+            "" => format!("{}/", target),
+            query => format!("{}/?{}", target, query),
+        };
+        return Ok(
+            HttpResponse::status(301, "Moved Permanently").with_header("location", &location)
+        );
+    }
+
+    let query = match Query::parse(query) {
+        Ok(query) => query,
+        Err(reason) => return Ok(HttpResponse::error(400, "Bad Request", reason)),
+    };
+
+    let mut entries = read_entries(path, options).await?;
+    sort(&mut entries, &query);
+    let total = entries.len();
+    let start = (query.page - 1).saturating_mul(query.per_page).min(total);
+    let end = start.saturating_add(query.per_page).min(total);
+    let page = &entries[start..end];
+
+    // the target was resolved to `path`, so it decodes
+    let decoded = path::percent_decode(target).unwrap_or_default();
+    let display_path = String::from_utf8_lossy(&decoded);
+
+    let response = if prefers_json(&request.headers) {
+        let listing = Listing {
+            path: &display_path,
+            page: query.page,
+            per_page: query.per_page,
+            total,
+            entries: page,
+        };
+        let content = serde_json::to_vec(&listing).expect("listings serialize");
+        HttpResponse::ok("application/json", content)
+    } else {
+        let content = html(target == "/files/", &display_path, &query, page, total);
+        HttpResponse::ok("text/html; charset=utf-8", content.into_bytes())
+    };
+
+    Ok(response
+        .with_vary("accept")
+        .with_header("x-content-type-options", "nosniff"))
+}
+
+/// Reads the names of the entries of a directory a client may see, with the metadata of the
+/// files they lead to. Names that aren't UTF-8 can't be requested, so they're left out, and so
+/// are hidden files unless they're to be shown, links when those aren't allowed, and links
+/// that are dangling.
+pub async fn read_visible(
+    path: &Path,
+    options: &FileOptions,
+) -> io::Result<Vec<(String, Metadata)>> {
+    let mut entries = Vec::new();
+    let mut dir = fs::read_dir(path).await?;
+
+    while let Some(entry) = dir.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') && !options.show_hidden {
+            continue;
+        }
+        if entry.file_type().await?.is_symlink() && options.symlinks == SymlinkPolicy::Deny {
+            continue;
+        }
+        if let Ok(metadata) = fs::metadata(entry.path()).await {
+            entries.push((name, metadata));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Reads the entries of a directory that may be listed.
+async fn read_entries(path: &Path, options: &FileOptions) -> io::Result<Vec<Entry>> {
+    let entries = read_visible(path, options).await?;
+
+    Ok(entries
+        .into_iter()
+        .map(|(name, metadata)| {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_secs());
+            match metadata.is_dir() {
+                true => Entry {
+                    name,
+                    kind: Kind::Directory,
+                    size: None,
+                    modified,
+                },
+                false => Entry {
+                    name,
+                    kind: Kind::File,
+                    size: Some(metadata.len()),
+                    modified,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Sorts entries as the query asks, directories first and then by name among equals.
+fn sort(entries: &mut [Entry], query: &Query) {
+    entries.sort_by(|a, b| {
+        let order = match query.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if query.descending {
+            order.reverse()
+        } else {
+            order
+        };
+
+        let directories_first = (b.kind == Kind::Directory).cmp(&(a.kind == Kind::Directory));
+        directories_first.then(order)
+    });
+}
+
+/// Whether the `Accept` field of a request ranks JSON above HTML. Without the field, HTML is
+/// preferred.
+fn prefers_json(headers: &Headers) -> bool {
+    let ranges: Vec<_> = headers.get_list("accept").filter_map(weighted).collect();
+
+    quality(&ranges, "application/json") > quality(&ranges, "text/html")
+}
+
+/// The quality the most specific of the media ranges matching `media_type` gives it.
+fn quality(ranges: &[(String, u16)], media_type: &str) -> u16 {
+    let (kind, _) = media_type.split_once('/').unwrap();
+    ranges
+        .iter()
+        .filter_map(|(range, quality)| {
+            let specificity = match range.split_once('/') {
+                _ if range == media_type => 2,
+                Some((range_kind, "*")) if range_kind == kind => 1,
+                Some(("*", "*")) => 0,
+                _ => return None,
+            };
+            Some((specificity, *quality))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0, |(_, quality)| quality)
+}
+
+/// Renders a page of a listing as an HTML document, with links to sort it by each column and
+/// to the pages before and after it.
+fn html(root: bool, path: &str, query: &Query, entries: &[Entry], total: usize) -> String {
+    let title = format!("Index of {}", escape(path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n\
+         </head>\n<body>\n<h1>{0}</h1>\n<table>\n<tr>",
+        title
+    );
+
+    for (key, label) in [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Last modified"),
+    ] {
+        // clicking the column the listing is sorted by reverses the order
+        let descending = key == query.sort && !query.descending;
+        let href = query.href(key, descending, 1);
+        write!(html, "<th><a href=\"{}\">{}</a></th>", escape(&href), label).unwrap();
+    }
+    html.push_str("</tr>\n");
+
+    if !root {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let (suffix, size) = match entry.size {
+            Some(size) => ("", size.to_string()),
+            None => ("/", "-".to_string()),
+        };
+        let modified = entry.modified.map_or("-".to_string(), |secs| {
+            httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs))
+        });
+        writeln!(
+            html,
+            "<tr><td><a href=\"{}{suffix}\">{}{suffix}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encode(&entry.name),
+            escape(&entry.name),
+            size,
+            modified,
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+
+    let pages = total.div_ceil(query.per_page).max(1);
+    if pages > 1 {
+        html.push_str("<p>");
+        if query.page > 1 {
+            let href = query.href(query.sort, query.descending, query.page - 1);
+            write!(html, "<a href=\"{}\">Previous</a> ", escape(&href)).unwrap();
+        }
+        write!(html, "Page {} of {}", query.page, pages).unwrap();
+        if query.page < pages {
+            let href = query.href(query.sort, query.descending, query.page + 1);
+            write!(html, " <a href=\"{}\">Next</a>", escape(&href)).unwrap();
+        }
+        html.push_str("</p>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Escapes text for use in HTML or XML content or attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes a file name for use as a relative reference, so that none of its
+/// characters can be taken for a delimiter.
+pub fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        listener::{request::RequestReader, testing::TempDir},
+        options::ServerOptions,
+    };
+    use bytes::Bytes;
+
+    /// A directory with a few files, a subdirectory and a hidden file.
+    fn listed_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        dir.write("b.txt", "bb");
+        dir.write("a <&>.txt", "aaa");
+        dir.write("c.txt", "c");
+        dir.write(".hidden", "");
+        dir
+    }
+
+    async fn list(path: &Path, target: &str, headers: &str, files: &FileOptions) -> HttpResponse {
+        let input = format!("GET {} HTTP/1.1\r\n{}\r\n", target, headers);
+        let options = ServerOptions::default();
+        let mut reader = RequestReader::new(input.as_bytes(), options.limits, options.timeouts);
+        let request = reader.read().await.unwrap().unwrap();
+        get(&request, path, files).await.unwrap()
+    }
+
+    fn names(response: &HttpResponse) -> Vec<String> {
+        let listing: serde_json::Value =
+            serde_json::from_slice(response.content.bytes().unwrap()).unwrap();
+        listing["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    const JSON: &str = "accept: application/json\r\n";
+
+    #[test]
+    fn parse_queries() {
+        assert_eq!(Query::parse(""), Ok(Query::default()));
+        assert_eq!(
+            Query::parse("sort=size&order=desc&page=3&per_page=10&other=x"),
+            Ok(Query {
+                sort: SortKey::Size,
+                descending: true,
+                page: 3,
+                per_page: 10,
+            })
+        );
+
+        for query in [
+            "sort=owner",
+            "order=up",
+            "page=0",
+            "page=x",
+            "per_page=1001",
+        ] {
+            assert!(Query::parse(query).is_err(), "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn negotiate_format() {
+        let cases = [
+            ("", false),
+            ("application/json", true),
+            ("text/html, application/json", false),
+            ("text/html;q=0.9, application/json", true),
+            ("application/*", true),
+            ("*/*", false),
+            ("*/*, application/json;q=0", false),
+            ("text/*;q=0.5, */*", true),
+        ];
+
+        for (accept, json) in cases {
+            let fields = match accept {
+                "" => vec![],
+                accept => vec![(Bytes::from("accept"), Bytes::from(accept))],
+            };
+            assert_eq!(prefers_json(&Headers::new(fields)), json, "{:?}", accept);
+        }
+    }
+
+    #[tokio::test]
+    async fn list_as_html() {
+        let dir = listed_dir("html");
+        let response = list(dir.path(), "/files/dir/", "", &FileOptions::default()).await;
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.header("vary"), Some("accept"));
+        let html = String::from_utf8(response.content.bytes().unwrap().to_vec()).unwrap();
+        assert!(html.contains("<title>Index of /files/dir/</title>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("<a href=\"sub/\">sub/</a></td><td>-</td>"));
+        assert!(
+            html.contains("<a href=\"a%20%3C%26%3E.txt\">a &lt;&amp;&gt;.txt</a></td><td>3</td>")
+        );
+        assert!(!html.contains("hidden"));
+
+        let response = list(dir.path(), "/files/", "", &FileOptions::default()).await;
+        let html = String::from_utf8(response.content.bytes().unwrap().to_vec()).unwrap();
+        assert!(!html.contains("../"));
+    }
+
+    #[tokio::test]
+    async fn list_as_json() {
+        let dir = listed_dir("json");
+        let response = list(dir.path(), "/files/", JSON, &FileOptions::default()).await;
+
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        let listing: serde_json::Value =
+            serde_json::from_slice(response.content.bytes().unwrap()).unwrap();
+        assert_eq!(listing["path"], "/files/");
+        assert_eq!(listing["total"], 4);
+        assert_eq!(listing["entries"][0]["name"], "sub");
+        assert_eq!(listing["entries"][0]["type"], "directory");
+        assert_eq!(listing["entries"][0]["size"], serde_json::Value::Null);
+        assert_eq!(listing["entries"][1]["type"], "file");
+        assert_eq!(listing["entries"][1]["size"], 3);
+        assert!(listing["entries"][1]["modified"].is_u64());
+
+        let options = FileOptions {
+            show_hidden: true,
+            ..FileOptions::default()
+        };
+        let response = list(dir.path(), "/files/", JSON, &options).await;
+        assert_eq!(
+            names(&response),
+            ["sub", ".hidden", "a <&>.txt", "b.txt", "c.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn sort_and_paginate() {
+        let dir = listed_dir("sorted");
+        let options = FileOptions::default();
+
+        let response = list(dir.path(), "/files/?sort=size&order=desc", JSON, &options).await;
+        assert_eq!(names(&response), ["sub", "a <&>.txt", "b.txt", "c.txt"]);
+        let response = list(dir.path(), "/files/?sort=size", JSON, &options).await;
+        assert_eq!(names(&response), ["sub", "c.txt", "b.txt", "a <&>.txt"]);
+
+        let response = list(dir.path(), "/files/?per_page=3&page=2", JSON, &options).await;
+        assert_eq!(names(&response), ["c.txt"]);
+        let response = list(dir.path(), "/files/?per_page=3&page=9", JSON, &options).await;
+        assert!(names(&response).is_empty());
+
+        let response = list(dir.path(), "/files/?per_page=3&page=2", "", &options).await;
+        let html = String::from_utf8(response.content.bytes().unwrap().to_vec()).unwrap();
+        assert!(html.contains("<a href=\"?sort=name&amp;order=asc&amp;page=1&amp;per_page=3\">Previous</a> Page 2 of 2</p>"));
+
+        let response = list(dir.path(), "/files/?sort=owner", "", &options).await;
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[tokio::test]
+    async fn redirect_to_trailing_slash() {
+        let dir = listed_dir("redirect");
+        let response = list(
+            dir.path(),
+            "/files/dir?sort=size",
+            "",
+            &FileOptions::default(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 301);
+        assert_eq!(response.header("location"), Some("/files/dir/?sort=size"));
+    }
+}
@@ -0,0 +1,228 @@
+//! WebDAV write locks (RFC 4918 section 6), kept in memory for as long as the server runs.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Longest a lock lasts without being refreshed. Clients asking for longer, or for no
+/// timeout at all, get this.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Most locks a file may have at once. Only shared locks can add up to this; past it, more
+/// are refused as if they conflicted, so clients can't fill the server's memory with them.
+pub const MAX_LOCKS: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct Lock {
+    /// The `urn:uuid:` URI clients submit to show they hold the lock.
+    pub token: String,
+    /// The locked file or directory.
+    pub root: PathBuf,
+    pub exclusive: bool,
+    /// Whether the lock extends to everything in a locked directory.
+    pub infinite: bool,
+    /// The owner element the client gave, written out, which is reported back as is.
+    pub owner: String,
+    pub timeout: Duration,
+    expires: Instant,
+}
+
+impl Lock {
+    pub fn new(
+        root: PathBuf,
+        exclusive: bool,
+        infinite: bool,
+        owner: String,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            token: new_token(),
+            root,
+            exclusive,
+            infinite,
+            owner,
+            timeout,
+            expires: Instant::now() + timeout,
+        }
+    }
+
+    /// Whether the lock applies to `path`.
+    pub fn covers(&self, path: &Path) -> bool {
+        path == self.root || (self.infinite && path.starts_with(&self.root))
+    }
+
+    /// How long until the lock expires.
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Locks held on files under the root directory. Expired ones are dropped as they're found.
+#[derive(Debug, Default)]
+pub struct Locks(Mutex<Vec<Lock>>);
+
+impl Locks {
+    /// Locks that apply to `path`: those on it, and those on a directory above it that extend
+    /// to its contents.
+    pub fn covering(&self, path: &Path) -> Vec<Lock> {
+        self.live(|locks| {
+            locks
+                .iter()
+                .filter(|lock| lock.covers(path))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Locks on `path` or anything inside it.
+    pub fn within(&self, path: &Path) -> Vec<Lock> {
+        self.live(|locks| {
+            locks
+                .iter()
+                .filter(|lock| lock.root.starts_with(path))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Adds `lock`, unless it conflicts with one already held: any lock overlapping an
+    /// exclusive one does. Returns the lock added, or `None` on a conflict or if the file has
+    /// `MAX_LOCKS` already.
+    pub fn lock(&self, lock: Lock) -> Option<Lock> {
+        self.live(|locks| {
+            let conflict = locks.iter().any(|held| {
+                let overlaps = held.covers(&lock.root) || lock.covers(&held.root);
+                overlaps && (held.exclusive || lock.exclusive)
+            });
+            let held = locks.iter().filter(|held| held.root == lock.root).count();
+            if conflict || held >= MAX_LOCKS {
+                return None;
+            }
+
+            locks.push(lock.clone());
+            Some(lock)
+        })
+    }
+
+    /// Extends the lock with `token` that applies to `path` by `timeout` from now. Returns
+    /// the refreshed lock, or `None` if there's no such lock.
+    pub fn refresh(&self, path: &Path, token: &str, timeout: Duration) -> Option<Lock> {
+        self.live(|locks| {
+            let lock = locks
+                .iter_mut()
+                .find(|lock| lock.token == token && lock.covers(path))?;
+            lock.timeout = timeout;
+            lock.expires = Instant::now() + timeout;
+            Some(lock.clone())
+        })
+    }
+
+    /// Removes the lock with `token` that applies to `path`. Returns whether there was one.
+    pub fn unlock(&self, path: &Path, token: &str) -> bool {
+        self.live(|locks| {
+            let count = locks.len();
+            locks.retain(|lock| !(lock.token == token && lock.covers(path)));
+            locks.len() < count
+        })
+    }
+
+    /// Removes the locks on `path` and anything inside it, for when those files are removed
+    /// or moved away.
+    pub fn remove_within(&self, path: &Path) {
+        self.live(|locks| locks.retain(|lock| !lock.root.starts_with(path)))
+    }
+
+    /// Runs `f` on the locks that haven't expired.
+    fn live<T>(&self, f: impl FnOnce(&mut Vec<Lock>) -> T) -> T {
+        let mut locks = self.0.lock().unwrap();
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        f(&mut locks)
+    }
+}
+
+/// A lock token, as a URN with a random version 4 UUID (RFC 9562).
+fn new_token() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    let (high, low) = (random(), random());
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "urn:uuid:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(root: &str, exclusive: bool, infinite: bool) -> Lock {
+        Lock::new(
+            PathBuf::from(root),
+            exclusive,
+            infinite,
+            String::new(),
+            MAX_TIMEOUT,
+        )
+    }
+
+    #[test]
+    fn generate_uuid_tokens() {
+        let token = new_token();
+        let uuid = token.strip_prefix("urn:uuid:").unwrap();
+        let groups: Vec<_> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        assert!("89ab".contains(&uuid[19..20]));
+        assert_ne!(token, new_token());
+    }
+
+    #[test]
+    fn refuse_conflicting_locks() {
+        let locks = Locks::default();
+        let shared = locks.lock(lock("/r/a", false, true)).unwrap();
+        assert!(locks.lock(lock("/r/a", false, false)).is_some());
+        assert!(locks.lock(lock("/r/a/b", true, false)).is_none());
+        assert!(locks.lock(lock("/r", true, true)).is_none());
+        assert!(locks.lock(lock("/r", true, false)).is_some());
+        assert!(locks.lock(lock("/r/c", true, false)).is_some());
+
+        assert_eq!(locks.covering(Path::new("/r/a/b")).len(), 1);
+        assert_eq!(locks.within(Path::new("/r/a")).len(), 2);
+        assert!(!locks.unlock(Path::new("/r/c"), &shared.token));
+        assert!(locks.unlock(Path::new("/r/a/b"), &shared.token));
+        assert!(locks.lock(lock("/r/a/b", true, false)).is_some());
+
+        locks.remove_within(Path::new("/r/a"));
+        assert_eq!(locks.within(Path::new("/r")).len(), 2);
+    }
+
+    #[test]
+    fn limit_locks_per_file() {
+        let locks = Locks::default();
+        for _ in 0..MAX_LOCKS {
+            assert!(locks.lock(lock("/r/a", false, false)).is_some());
+        }
+        assert!(locks.lock(lock("/r/a", false, false)).is_none());
+        assert!(locks.lock(lock("/r/b", false, false)).is_some());
+    }
+
+    #[test]
+    fn expire_locks() {
+        let locks = Locks::default();
+        let held = locks.lock(lock("/r/a", true, false)).unwrap();
+        let refreshed = locks.refresh(Path::new("/r/a"), &held.token, Duration::ZERO);
+        assert!(refreshed.is_some());
+        assert!(locks.covering(Path::new("/r/a")).is_empty());
+        assert!(locks.lock(lock("/r/a", true, false)).is_some());
+    }
+}
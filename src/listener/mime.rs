@@ -0,0 +1,215 @@
+//! Working out the media type of a file from its extension, or from its first bytes.
+
+use std::{collections::HashMap, path::Path};
+
+/// How many bytes at the start of a file are looked at to sniff its type.
+pub const SNIFF_LEN: usize = 512;
+
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Media types by file extension. Options can add to these or override them.
+const TYPES: &[(&str, &str)] = &[
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("md", "text/markdown"),
+    ("mjs", "text/javascript"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("oga", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+/// Signatures of common binary formats, as a prefix and the type it indicates.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b\x08", "application/gzip"),
+    (b"\0asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+];
+
+/// The media type of the file at `path`, going by its extension. `overrides` maps lowercase
+/// extensions to types, taking precedence over the built-in table. Returns `None` for files
+/// without an extension, whose type may be sniffed instead.
+pub fn from_extension(path: &Path, overrides: &HashMap<String, String>) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+    let media_type = match overrides.get(&extension) {
+        Some(media_type) => media_type.as_str(),
+        None => TYPES
+            .binary_search_by_key(&extension.as_str(), |(extension, _)| extension)
+            .map_or(DEFAULT_TYPE, |i| TYPES[i].1),
+    };
+
+    Some(with_charset(media_type))
+}
+
+/// Guesses the media type of a file from its first bytes. Only some common formats are
+/// recognized, and anything else that is UTF-8 text without control characters is plain text.
+pub fn sniff(start: &[u8]) -> String {
+    if let Some((_, media_type)) = SIGNATURES.iter().find(|(sig, _)| start.starts_with(sig)) {
+        return media_type.to_string();
+    }
+    if start.starts_with(b"RIFF") && start.get(8..12) == Some(b"WEBP") {
+        return "image/webp".to_string();
+    }
+
+    let text = match std::str::from_utf8(start) {
+        Ok(text) => text,
+        // the sniffed bytes may end in the middle of a character
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&start[..err.valid_up_to()]).unwrap()
+        }
+        Err(_) => return DEFAULT_TYPE.to_string(),
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !c.is_ascii_whitespace())
+    {
+        return DEFAULT_TYPE.to_string();
+    }
+
+    let lowercase = text.trim_start().to_ascii_lowercase();
+    let media_type = if lowercase.starts_with("<!doctype html") || lowercase.starts_with("<html") {
+        "text/html"
+    } else if lowercase.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain"
+    };
+
+    with_charset(media_type)
+}
+
+/// Whether a media type from configuration looks valid: a type and subtype made of token
+/// characters, optionally followed by parameters.
+pub fn is_valid(media_type: &str) -> bool {
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+    };
+
+    if media_type.chars().any(char::is_control) {
+        return false;
+    }
+
+    let essence = media_type.split(';').next().unwrap().trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) => is_token(kind) && is_token(subtype),
+        None => false,
+    }
+}
+
+/// Adds a UTF-8 charset parameter to textual types that don't have one.
+fn with_charset(media_type: &str) -> String {
+    let textual = media_type.starts_with("text/") || media_type == "application/javascript";
+    if textual && !media_type.contains(';') {
+        format!("{}; charset=utf-8", media_type)
+    } else {
+        media_type.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_are_sorted() {
+        assert!(TYPES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn detect_from_extension() {
+        let overrides = HashMap::from([
+            ("log".to_string(), "text/plain".to_string()),
+            ("json".to_string(), "application/x-custom".to_string()),
+        ]);
+        let cases = [
+            ("index.html", Some("text/html; charset=utf-8")),
+            ("INDEX.HTM", Some("text/html; charset=utf-8")),
+            ("photo.JPG", Some("image/jpeg")),
+            ("archive.tar.gz", Some("application/gzip")),
+            ("data.json", Some("application/x-custom")),
+            ("server.log", Some("text/plain; charset=utf-8")),
+            ("blob.unknown", Some("application/octet-stream")),
+            ("README", None),
+            ("dir/.hidden", None),
+        ];
+
+        for (path, expected) in cases {
+            let media_type = from_extension(Path::new(path), &overrides);
+            assert_eq!(media_type.as_deref(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn sniff_content() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", "image/jpeg"),
+            (b"RIFF\0\0\0\0WEBPVP8 ", "image/webp"),
+            (b"%PDF-1.7\n", "application/pdf"),
+            (b"\n  <!DOCTYPE html><html>", "text/html; charset=utf-8"),
+            (b"<?xml version=\"1.0\"?>", "application/xml"),
+            (
+                b"plain text\r\nwith lines\tand tabs",
+                "text/plain; charset=utf-8",
+            ),
+            ("caf\u{e9}".as_bytes(), "text/plain; charset=utf-8"),
+            (&"\u{e9}".as_bytes()[..1], "text/plain; charset=utf-8"),
+            (b"", "text/plain; charset=utf-8"),
+            (b"text\0with nul", "application/octet-stream"),
+            (b"\xff\xfe\xfd", "application/octet-stream"),
+        ];
+
+        for (start, expected) in cases {
+            assert_eq!(sniff(start), *expected, "{:?}", start);
+        }
+    }
+
+    #[test]
+    fn validate_media_types() {
+        assert!(is_valid("text/plain"));
+        assert!(is_valid("text/plain; charset=iso-8859-1"));
+        assert!(is_valid("application/vnd.api+json"));
+        assert!(!is_valid("text"));
+        assert!(!is_valid("text/"));
+        assert!(!is_valid("text/plain\r\nx-injected: 1"));
+        assert!(!is_valid("te xt/plain"));
+        assert!(!is_valid("text/plain; x=\r\nx-injected: 1"));
+    }
+}
@@ -1,55 +1,189 @@
+mod body;
+mod compression;
+mod conditional;
+mod files;
 mod handler;
+mod headers;
+mod listing;
+mod locks;
+pub mod mime;
+mod parser;
+mod path;
+mod range;
 mod request;
+#[cfg(test)]
+mod testing;
+mod upload;
+mod webdav;
+mod xml;
 
-use crate::options::ServerOptions;
-use std::io::Result;
+use crate::{log, options::ServerOptions};
+use body::Body;
+use bytes::Bytes;
+use compression::AcceptEncoding;
+use request::{HttpRequestError, RequestBody, RequestReader};
+use std::{
+    future::Future,
+    io::{ErrorKind, Result},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream, ToSocketAddrs,
+        TcpListener, TcpStream,
     },
-    sync::mpsc,
+    sync::{mpsc, oneshot, watch},
     task::{JoinHandle, JoinSet},
+    time,
 };
 
-pub fn start<A: ToSocketAddrs + Send + 'static>(
-    addr: A,
+/// A response that is still being produced by its handler. These are queued to the writer in
+/// the order requests were read, so responses to pipelined requests are written in that same
+/// order even though handlers run concurrently.
+type PendingResponse = oneshot::Receiver<HttpResponse>;
+
+/// How long to keep discarding input from a client after deciding to close its connection, so
+/// that unread data doesn't make the socket send a reset before the client reads our response.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many pieces of a request body can be read ahead of the handler consuming them.
+const BODY_BOUND: usize = 4;
+
+/// Starts accepting connections on `addrs` until `shutdown` completes. Connections then finish
+/// the requests they're processing and close, and the returned handle resolves once they all
+/// have, or once the shutdown timeout runs out.
+pub fn start<S>(
+    addrs: Vec<SocketAddr>,
     options: ServerOptions,
-) -> JoinHandle<Result<()>> {
+    shutdown: S,
+) -> JoinHandle<Result<()>>
+where
+    S: Future<Output = ()> + Send + 'static,
+{
     tokio::spawn(async move {
-        let listener = TcpListener::bind(addr).await?;
+        // bind everything before accepting anything, so a bad address fails the whole server
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            listeners.push(TcpListener::bind(addr).await?);
+        }
+
+        let (streams_tx, mut streams_rx) = mpsc::channel(listeners.len());
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            let streams_tx = streams_tx.clone();
+            acceptors.spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    if streams_tx.send(stream).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(streams_tx);
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let dav = Arc::new(webdav::State::default());
         let mut tasks = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            let stream = tokio::select! {
+                stream = streams_rx.recv() => match stream {
+                    Some(stream) => stream,
+                    None => break,
+                },
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => continue,
+                _ = &mut shutdown => break,
+            };
 
-        while let Ok((stream, _)) = listener.accept().await {
             let options = options.clone();
-            tasks.spawn(async move { handle_connection(options, stream).await });
+            let dav = dav.clone();
+            let shutdown_rx = shutdown_rx.clone();
+            tasks.spawn(async move { handle_connection(options, dav, stream, shutdown_rx).await });
+        }
+
+        acceptors.shutdown().await;
+        let _ = shutdown_tx.send(true);
+        log::info!("shutting down, waiting for {} connections", tasks.len());
+
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if time::timeout(options.timeouts.shutdown, drain)
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "shutdown timeout reached, closing {} connections",
+                tasks.len()
+            );
+            tasks.shutdown().await;
         }
 
-        while tasks.join_next().await.is_some() {}
         Ok(())
     })
 }
 
-async fn handle_connection(options: ServerOptions, stream: TcpStream) -> Result<()> {
+async fn handle_connection(
+    options: ServerOptions,
+    dav: Arc<webdav::State>,
+    stream: TcpStream,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
     let (read_half, write_half) = stream.into_split();
-    let (writer_handle, responses_tx) = start_writer(write_half);
-    let reader_handle = start_reader(options, read_half, responses_tx);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    // dropping the join set aborts both tasks, in case this connection is cancelled
+    let mut tasks = JoinSet::new();
+    let sendfile = options.files.sendfile;
+    let responses_tx = start_writer(&mut tasks, write_half, in_flight.clone(), sendfile);
+    start_reader(
+        &mut tasks,
+        options,
+        dav,
+        read_half,
+        responses_tx,
+        in_flight,
+        shutdown,
+    );
 
-    let _ = tokio::join!(writer_handle, reader_handle);
+    while tasks.join_next().await.is_some() {}
 
     Ok(())
 }
 
+/// Starts the task that writes responses to the client. `in_flight` is decremented after each
+/// response is written. With `sendfile`, file content is written with `sendfile(2)` where
+/// that's available.
 fn start_writer(
+    tasks: &mut JoinSet<Result<()>>,
     write_half: OwnedWriteHalf,
-) -> (JoinHandle<Result<()>>, mpsc::Sender<HttpResponse>) {
-    let (tx, mut rx) = mpsc::channel::<HttpResponse>(5);
+    in_flight: Arc<AtomicUsize>,
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))] sendfile: bool,
+) -> mpsc::Sender<PendingResponse> {
+    let (tx, mut rx) = mpsc::channel::<PendingResponse>(5);
 
-    let handle = tokio::spawn(async move {
+    tasks.spawn(async move {
         let mut writer = BufWriter::new(write_half);
 
-        while let Some(response) = rx.recv().await {
+        while let Some(pending) = rx.recv().await {
+            let response = match pending.await {
+                Ok(response) => response,
+                Err(_) => {
+                    log::error!("handler finished without producing a response");
+                    HttpResponse::status(500, "Internal Server Error")
+                }
+            };
+
+            let chunked = response.is_chunked();
+            let may_have_content = response.may_have_content();
+            // content of unknown length that isn't in chunks ends when the connection closes
+            let closes_connection = response.closes_connection()
+                || (may_have_content && !chunked && response.content.len().is_none());
             let status = format!(
                 "HTTP/1.1 {} {}\r\n",
                 response.status_code, response.status_line
@@ -57,8 +191,12 @@ fn start_writer(
 
             writer.write_all(status.as_bytes()).await?;
 
-            if !response.has_content_length() {
-                let header = format!("content-length: {}\r\n", response.content.len());
+            if let (false, true, Some(len)) = (
+                response.has_content_length(),
+                may_have_content,
+                response.content.len(),
+            ) {
+                let header = format!("content-length: {}\r\n", len);
                 writer.write_all(header.as_bytes()).await?;
             }
 
@@ -68,61 +206,194 @@ fn start_writer(
             }
 
             writer.write_all("\r\n".as_bytes()).await?;
-            writer.write_all(response.content.as_slice()).await?;
+            match response.content {
+                _ if !may_have_content => {}
+                #[cfg(target_os = "linux")]
+                Body::File { file, range } if sendfile => {
+                    writer.flush().await?;
+                    body::sendfile(writer.get_ref().as_ref(), &file, range).await?;
+                }
+                content => content.write_to(&mut writer, chunked).await?,
+            }
             writer.flush().await?;
+            in_flight.fetch_sub(1, Ordering::Release);
+
+            if closes_connection {
+                break;
+            }
         }
 
+        writer.shutdown().await?;
         Ok(())
     });
 
-    (handle, tx)
+    tx
 }
 
+/// Starts the task that reads requests from the client. `in_flight` is incremented for each
+/// response queued to the writer. Once `shutdown` is set, the connection is closed after the
+/// requests already being processed.
 fn start_reader(
+    tasks: &mut JoinSet<Result<()>>,
     options: ServerOptions,
+    dav: Arc<webdav::State>,
     read_half: OwnedReadHalf,
-    tx: mpsc::Sender<HttpResponse>,
-) -> JoinHandle<Result<()>> {
-    tokio::spawn(async move { read_loop(options, read_half, tx).await })
+    tx: mpsc::Sender<PendingResponse>,
+    in_flight: Arc<AtomicUsize>,
+    shutdown: watch::Receiver<bool>,
+) {
+    tasks.spawn(async move { read_loop(options, dav, read_half, tx, in_flight, shutdown).await });
 }
 
 async fn read_loop(
     options: ServerOptions,
+    dav: Arc<webdav::State>,
     read_half: OwnedReadHalf,
-    tx: mpsc::Sender<HttpResponse>,
+    tx: mpsc::Sender<PendingResponse>,
+    in_flight: Arc<AtomicUsize>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
-    let mut reader = BufReader::new(read_half);
+    let mut reader =
+        RequestReader::new(read_half, options.limits.clone(), options.timeouts.clone());
     let mut tasks = JoinSet::new();
 
     loop {
-        match request::read(&mut reader).await {
-            Ok(Some(request)) => {
-                let tx = tx.clone();
+        let ready = tokio::select! {
+            ready = reader.wait_for_request() => ready,
+            _ = tx.closed() => break,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+
+        match ready {
+            Ok(true) => {}
+            // the connection isn't idle while responses are still being sent
+            Err(error)
+                if error.kind() == ErrorKind::TimedOut && in_flight.load(Ordering::Acquire) > 0 =>
+            {
+                continue
+            }
+            Ok(false) | Err(_) => break,
+        }
+
+        let result = tokio::select! {
+            result = reader.read_head() => result,
+            _ = tx.closed() => break,
+        };
+
+        match result {
+            Ok(Some(mut request)) => {
+                let (response_tx, response_rx) = oneshot::channel();
+                in_flight.fetch_add(1, Ordering::Release);
+                if tx.send(response_rx).await.is_err() {
+                    break;
+                }
+
+                let (body_tx, body_rx) = mpsc::channel(BODY_BOUND);
+                let (forwarded_tx, forwarded_rx) = oneshot::channel();
+                if reader.has_body() {
+                    request.body = RequestBody::Stream(body_rx);
+                }
+
+                let keep_alive = request.keeps_alive() && !*shutdown.borrow();
+                let accept_encoding = AcceptEncoding::from_headers(&request.headers);
+                let chunks_allowed = request.version() != "HTTP/1.0";
                 let options = options.clone();
+                let dav = dav.clone();
+                let shutdown = shutdown.clone();
                 tasks.spawn(async move {
-                    let response = handler::handle(options, request).await;
-                    let _ = tx.send(response).await;
+                    let compression = options.compression.clone();
+                    let response = match compression::decode_request(&compression, request).await {
+                        Ok(request) => handler::handle(options, &dav, request).await,
+                        Err(error) => {
+                            log::debug!("error decoding request body: {}", error);
+                            error.to_response()
+                        }
+                    };
+                    let response =
+                        compression::encode_response(&compression, &accept_encoding, response);
+                    // the connection stays open only if the body could be read to its end
+                    let keep_alive =
+                        keep_alive && forwarded_rx.await.unwrap_or(false) && !*shutdown.borrow();
+                    let response = response
+                        .with_framing(chunks_allowed)
+                        .with_connection(keep_alive);
+                    let _ = response_tx.send(response);
                 });
+
+                let forwarded = tokio::select! {
+                    forwarded = forward_body(&mut reader, body_tx) => forwarded,
+                    _ = tx.closed() => break,
+                };
+                let _ = forwarded_tx.send(forwarded);
+
+                if !forwarded || !keep_alive {
+                    break;
+                }
             }
             Ok(None) => {
                 break;
             }
             Err(error) => {
-                let response = HttpResponse::status(400, error.to_string());
-                let _ = tx.send(response).await;
+                log::debug!("error reading request: {}", error);
+                let (response_tx, response_rx) = oneshot::channel();
+                let _ = response_tx.send(error.to_response());
+                in_flight.fetch_add(1, Ordering::Release);
+
+                // the rest of the stream can't be reliably split into requests anymore, so the
+                // connection is closed once this response is written
+                let _ = tx.send(response_rx).await;
+                break;
             }
         }
     }
 
+    drop(tx);
+    linger(reader.into_inner()).await;
+    while tasks.join_next().await.is_some() {}
     Ok(())
 }
 
+/// Passes the body of the request just read on to its handler, as fast as the handler reads
+/// it. Whatever the handler leaves unread is read anyway and discarded, so the next request
+/// starts where this one ends. Returns `false` if the body couldn't be read, after passing the
+/// error on, since there's no telling where the next request starts then.
+async fn forward_body<R: AsyncRead + Unpin>(
+    reader: &mut RequestReader<R>,
+    body_tx: mpsc::Sender<std::result::Result<Bytes, HttpRequestError>>,
+) -> bool {
+    loop {
+        match reader.read_body().await {
+            Ok(Some(piece)) => {
+                // fails once the handler drops the body, after which pieces are discarded.
+                // Waiting for the handler to take more doesn't count against the client.
+                let waiting = time::Instant::now();
+                let _ = body_tx.send(Ok(piece)).await;
+                reader.pause_body_rate(waiting.elapsed());
+            }
+            Ok(None) => return true,
+            Err(error) => {
+                log::debug!("error reading request body: {}", error);
+                let _ = body_tx.send(Err(error)).await;
+                return false;
+            }
+        }
+    }
+}
+
+async fn linger<R: AsyncRead + Unpin>(mut reader: R) {
+    let mut buffer = [0u8; 4096];
+    let _ = tokio::time::timeout(LINGER_TIMEOUT, async {
+        while let Ok(1..) = reader.read(&mut buffer).await {}
+    })
+    .await;
+}
+
 #[derive(Debug)]
 struct HttpResponse {
     status_code: u16,
     status_line: String,
     headers: Vec<(String, String)>,
-    content: Vec<u8>,
+    content: Body,
 }
 
 impl HttpResponse {
@@ -131,7 +402,7 @@ impl HttpResponse {
             status_code,
             status_line: status_line.into(),
             headers: vec![],
-            content: vec![],
+            content: Body::Full(vec![]),
         }
     }
 
@@ -140,22 +411,507 @@ impl HttpResponse {
             status_code: 200,
             status_line: "OK".to_string(),
             headers: vec![("content-type".to_string(), content_type.into())],
-            content,
+            content: Body::Full(content),
         }
     }
 
-    fn ok_with_headers(content: Vec<u8>, headers: Vec<(String, String)>) -> Self {
+    fn error<T: Into<String>>(status_code: u16, status_line: T, message: String) -> Self {
         Self {
-            status_code: 200,
-            status_line: "OK".to_string(),
-            headers,
-            content,
+            status_code,
+            status_line: status_line.into(),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            content: Body::Full(format!("{}\n", message).into_bytes()),
         }
     }
 
+    fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds `name` to the fields listed in the vary header, creating it if needed.
+    fn with_vary(mut self, name: &str) -> Self {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case("vary"))
+        {
+            Some((_, value)) => {
+                if !value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case(name))
+                {
+                    value.push_str(", ");
+                    value.push_str(name);
+                }
+                self
+            }
+            None => self.with_header("vary", name),
+        }
+    }
+
+    fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Sets the connection header to the mode chosen for the request, unless the handler
+    /// already asked for the connection to be closed.
+    fn with_connection(mut self, keep_alive: bool) -> Self {
+        if self.closes_connection() {
+            return self;
+        }
+
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case("connection"));
+        self.with_header(
+            "connection",
+            if keep_alive { "keep-alive" } else { "close" },
+        )
+    }
+
+    /// Chooses how the end of content of unknown length is marked. It's sent in chunks if the
+    /// client supports them, which HTTP/1.0 clients don't, and ends when the connection closes
+    /// otherwise.
+    fn with_framing(self, chunks_allowed: bool) -> Self {
+        if !self.may_have_content() || self.content.len().is_some() {
+            self
+        } else if chunks_allowed {
+            self.with_header("transfer-encoding", "chunked")
+        } else {
+            self.with_connection(false)
+        }
+    }
+
+    fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding")
+            .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    }
+
+    fn closes_connection(&self) -> bool {
+        self.header("connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"))
+    }
+
+    /// Responses with these status codes never have content, nor a content length
+    /// (RFC 9110 sections 8.6, 15.3.5 and 15.4.5).
+    fn may_have_content(&self) -> bool {
+        !matches!(self.status_code, 100..=199 | 204 | 304)
+    }
+
     fn has_content_length(&self) -> bool {
         self.headers
             .iter()
             .any(|(key, _)| key.eq_ignore_ascii_case("content-length"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TempDir;
+    use tokio::io::AsyncWriteExt;
+
+    async fn connect(options: ServerOptions, shutdown: watch::Receiver<bool>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(options, Default::default(), stream, shutdown).await
+        });
+
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    fn options() -> ServerOptions {
+        ServerOptions::default()
+    }
+
+    async fn exchange(options: ServerOptions, request: &str) -> String {
+        String::from_utf8(exchange_bytes(options, request).await).unwrap()
+    }
+
+    async fn exchange_bytes(options: ServerOptions, request: &str) -> Vec<u8> {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut stream = connect(options, shutdown_rx).await;
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    /// Splits a response into its head, as text, and its content.
+    fn split_head(response: &[u8]) -> (&str, &[u8]) {
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (
+            std::str::from_utf8(&response[..end]).unwrap(),
+            &response[end..],
+        )
+    }
+
+    #[tokio::test]
+    async fn close_after_malformed_request() {
+        let response = exchange(
+            options(),
+            "GET /echo/abc HTTP/1.1\r\n\r\nGARBAGE\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(
+            response,
+            concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "content-length: 3\r\n",
+                "content-type: text/plain\r\n",
+                "vary: accept-encoding\r\n",
+                "connection: keep-alive\r\n",
+                "\r\n",
+                "abc",
+                "HTTP/1.1 400 Bad Request\r\n",
+                "content-length: 23\r\n",
+                "content-type: text/plain\r\n",
+                "connection: close\r\n",
+                "\r\n",
+                "malformed request line\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn close_when_client_asks() {
+        let response = exchange(
+            options(),
+            "GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn close_http_1_0_by_default() {
+        let response = exchange(options(), "GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n").await;
+
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_http_1_0_alive_when_asked() {
+        let response = exchange(
+            options(),
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(
+            response,
+            concat!(
+                "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: keep-alive\r\n\r\n",
+                "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_large_files() {
+        let dir = TempDir::new("large");
+        let root = dir.path();
+        let content: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join("large"), &content).unwrap();
+
+        for sendfile in [false, true] {
+            let mut options = options();
+            options.root = Some(root.to_path_buf());
+            options.files.sendfile = sendfile;
+            let response = exchange_bytes(
+                options,
+                "GET /files/large HTTP/1.1\r\nconnection: close\r\n\r\n",
+            )
+            .await;
+
+            let (head, body) = split_head(&response);
+            assert!(head.contains("content-length: 3000000\r\n"), "{}", head);
+            assert!(body == content, "sendfile: {}", sendfile);
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_content_of_unknown_length() {
+        let dir = TempDir::new("framing");
+        let root = dir.path();
+        let content = "all work and no play\n".repeat(100_000);
+        std::fs::write(root.join("large.txt"), &content).unwrap();
+        let mut options = options();
+        options.root = Some(root.to_path_buf());
+
+        // compressing a file as it's sent leaves its length unknown
+        let response = exchange_bytes(
+            options.clone(),
+            "GET /files/large.txt HTTP/1.1\r\naccept-encoding: gzip\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+        let (head, mut rest) = split_head(&response);
+        assert!(head.contains("transfer-encoding: chunked\r\n"), "{}", head);
+        assert!(!head.contains("content-length"));
+
+        let mut compressed = Vec::new();
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&rest[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            let data = &rest[line_end + 2..];
+            if size == 0 {
+                assert_eq!(data, b"\r\n");
+                break;
+            }
+            compressed.extend_from_slice(&data[..size]);
+            rest = data[size..].strip_prefix(b"\r\n").unwrap();
+        }
+        let decompressed = compression::Encoding::Gzip
+            .decompress(&compressed, content.len())
+            .unwrap();
+        assert!(decompressed == content.as_bytes());
+
+        // HTTP/1.0 clients don't support chunks, so the connection is closed after the content
+        let response = exchange_bytes(
+            options,
+            "GET /files/large.txt HTTP/1.0\r\naccept-encoding: gzip\r\nconnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        )
+        .await;
+        let (head, rest) = split_head(&response);
+        assert!(head.contains("connection: close\r\n"), "{}", head);
+        assert!(!head.contains("transfer-encoding") && !head.contains("content-length"));
+        let decompressed = compression::Encoding::Gzip
+            .decompress(rest, content.len())
+            .unwrap();
+        assert!(decompressed == content.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn discard_unread_bodies() {
+        let large = "x".repeat(1_000_000);
+        let request = format!(
+            concat!(
+                "GET /echo/a HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}",
+                "GET /echo/b HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n",
+                "5\r\nhello\r\n0\r\n\r\n",
+                "GET /echo/c HTTP/1.1\r\nconnection: close\r\n\r\n",
+            ),
+            large.len(),
+            large
+        );
+        let response = exchange(options(), &request).await;
+
+        let contents: Vec<_> = response
+            .split("HTTP/1.1 200 OK\r\n")
+            .skip(1)
+            .map(|response| response.split("\r\n\r\n").nth(1).unwrap())
+            .collect();
+        assert_eq!(contents, ["a", "b", "c"]);
+
+        // a body that can't be read closes the connection, even if the handler ignored it
+        let request = concat!(
+            "GET /echo/a HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n",
+            "5\r\nhello\r\nnot a chunk size\r\n\r\n",
+            "GET /echo/b HTTP/1.1\r\n\r\n",
+        );
+        let response = exchange(options(), request).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("connection: close\r\n"), "{}", response);
+        assert_eq!(response.matches("HTTP/1.1").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn stream_uploads_to_files() {
+        let dir = TempDir::new("upload");
+        let root = dir.path();
+        let mut options = options();
+        options.root = Some(root.to_path_buf());
+        options.limits.max_body_size = 4_000_000;
+
+        let content = "all work and no play\n".repeat(150_000);
+        let response = exchange(
+            options.clone(),
+            &format!(
+                "POST /files/upload HTTP/1.1\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                content.len(),
+                content
+            ),
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{}",
+            response
+        );
+        assert!(std::fs::read(root.join("upload")).unwrap() == content.as_bytes());
+
+        // a body that can't be read to the end fails the upload and closes the connection
+        let response = exchange(
+            options,
+            concat!(
+                "POST /files/broken HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n",
+                "5\r\nhello\r\nnot a chunk size\r\n\r\n",
+                "GET / HTTP/1.1\r\n\r\n",
+            ),
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("connection: close\r\n"), "{}", response);
+        assert_eq!(response.matches("HTTP/1.1").count(), 1);
+    }
+
+    /// Sends `head` and `body` on a kept-alive connection, and reads the response to it,
+    /// returning its status, head and content. The content is expected to have a length.
+    async fn round_trip(stream: &mut TcpStream, head: &str, body: &str) -> (u16, String, String) {
+        let request = format!("{}\r\ncontent-length: {}\r\n\r\n{}", head, body.len(), body);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(response).unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut content = vec![0; length];
+        stream.read_exact(&mut content).await.unwrap();
+
+        let status = head[9..12].parse().unwrap();
+        (status, head, String::from_utf8_lossy(&content).into_owned())
+    }
+
+    #[tokio::test]
+    async fn keep_webdav_state_across_requests() {
+        let dir = TempDir::new("webdav");
+        let mut options = options();
+        options.root = Some(dir.path().to_path_buf());
+        options.files.webdav = true;
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut stream = connect(options, shutdown_rx).await;
+
+        let (status, ..) = round_trip(&mut stream, "MKCOL /files/dir HTTP/1.1", "").await;
+        assert_eq!(status, 201);
+        let lockinfo = "<?xml version=\"1.0\"?><D:lockinfo xmlns:D=\"DAV:\">\
+            <D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>\
+            </D:lockinfo>";
+        let (status, head, _) = round_trip(&mut stream, "LOCK /files/dir HTTP/1.1", lockinfo).await;
+        assert_eq!(status, 200, "{}", head);
+        let token = head
+            .lines()
+            .find_map(|line| line.strip_prefix("lock-token: "))
+            .unwrap()
+            .to_string();
+
+        // everything in the locked directory takes its token
+        let put = "PUT /files/dir/a HTTP/1.1";
+        assert_eq!(round_trip(&mut stream, put, "a").await.0, 423);
+        let put = format!("{}\r\nif: ({})", put, token);
+        assert_eq!(round_trip(&mut stream, &put, "a").await.0, 201);
+        let rename = "MOVE /files/dir/a HTTP/1.1\r\ndestination: /files/dir/b";
+        assert_eq!(round_trip(&mut stream, rename, "").await.0, 423);
+        let rename = format!("{}\r\nif: ({})", rename, token);
+        assert_eq!(round_trip(&mut stream, &rename, "").await.0, 201);
+        let out = "MOVE /files/dir/b HTTP/1.1\r\ndestination: /files/b";
+        assert_eq!(round_trip(&mut stream, out, "").await.0, 423);
+
+        let unlock = format!("UNLOCK /files/dir HTTP/1.1\r\nlock-token: {}", token);
+        assert_eq!(round_trip(&mut stream, &unlock, "").await.0, 204);
+        assert_eq!(round_trip(&mut stream, out, "").await.0, 201);
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), b"a");
+
+        let list = "PROPFIND /files/dir HTTP/1.1\r\ndepth: 1\r\nconnection: close";
+        let (status, _, content) = round_trip(&mut stream, list, "").await;
+        assert_eq!(status, 207);
+        assert!(
+            content.contains("<D:href>/files/dir/</D:href>"),
+            "{}",
+            content
+        );
+        assert!(
+            !content.contains("<D:href>/files/dir/a") && !content.contains("<D:href>/files/dir/b")
+        );
+        assert!(!content.contains("activelock"), "{}", content);
+    }
+
+    #[tokio::test]
+    async fn revalidate_compressed_files() {
+        let dir = TempDir::new("revalidate");
+        let path = dir.write("page.html", "<p>all work and no play</p>\n".repeat(100));
+        // files modified within the last second only get weak entity tags
+        let modified = std::time::SystemTime::now() - Duration::from_secs(60);
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+        let mut options = options();
+        options.root = Some(dir.path().to_path_buf());
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut stream = connect(options, shutdown_rx).await;
+
+        let get = "GET /files/page.html HTTP/1.1\r\naccept-encoding: gzip";
+        let (status, head, _) = round_trip(&mut stream, get, "").await;
+        assert_eq!(status, 200);
+        assert!(head.contains("content-encoding: gzip\r\n"), "{}", head);
+        let etag = head
+            .lines()
+            .find_map(|line| line.strip_prefix("etag: "))
+            .unwrap()
+            .to_string();
+        assert!(
+            etag.starts_with('"') && etag.ends_with("-gzip\""),
+            "{}",
+            etag
+        );
+
+        // a 304 has the entity tag of what the client has cached
+        let revalidate = format!("{}\r\nif-none-match: {}", get, etag);
+        let (status, head, _) = round_trip(&mut stream, &revalidate, "").await;
+        assert_eq!(status, 304);
+        assert!(head.contains(&format!("etag: {}\r\n", etag)), "{}", head);
+        assert!(head.contains("vary: accept-encoding\r\n"), "{}", head);
+        let unencoded = format!("GET /files/page.html HTTP/1.1\r\nif-none-match: {}", etag);
+        let (status, head, _) = round_trip(&mut stream, &unencoded, "").await;
+        assert_eq!(status, 304);
+        assert!(!head.contains("-gzip"), "{}", head);
+
+        // and changes can be made conditional on it
+        let put = format!("PUT /files/page.html HTTP/1.1\r\nif-match: {}", etag);
+        assert_eq!(round_trip(&mut stream, &put, "new").await.0, 204);
+        assert_eq!(round_trip(&mut stream, &put, "newer").await.0, 412);
+        let (status, _, content) =
+            round_trip(&mut stream, "GET /files/page.html HTTP/1.1", "").await;
+        assert_eq!((status, content.as_str()), (200, "new"));
+    }
+
+    #[tokio::test]
+    async fn close_idle_connection_on_shutdown() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut stream = connect(options(), shutdown_rx).await;
+
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let expected = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: keep-alive\r\n\r\n";
+        let mut response = vec![0u8; expected.len()];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+
+        shutdown_tx.send(true).unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
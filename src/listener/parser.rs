@@ -0,0 +1,178 @@
+//! Parsers for the parts of an HTTP/1.1 request that are delimited by line endings, written
+//! with nom's streaming combinators: when the input ends before an element is complete they
+//! report `Incomplete` instead of failing, so the caller can read more and try again.
+
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, take_while, take_while1, take_while_m_n},
+    combinator::{map_res, recognize, value},
+    multi::{many0_count, many_till},
+    sequence::{terminated, tuple},
+    IResult,
+};
+use std::ops::Range;
+
+/// Request line elements, as offsets into the parsed input.
+#[derive(Debug, PartialEq)]
+pub struct RequestLine {
+    pub method: Range<usize>,
+    pub target: Range<usize>,
+    pub version: Range<usize>,
+}
+
+/// A header field name and value, as offsets into the parsed input.
+pub type HeaderField = (Range<usize>, Range<usize>);
+
+/// Parses a request line, skipping any empty lines before it as RFC 9112 section 2.2 suggests.
+pub fn request_line(input: &[u8]) -> IResult<&[u8], RequestLine> {
+    let (rest, (_, method, _, target, _, version, _)) = tuple((
+        many0_count(line_ending),
+        take_while1(is_tchar),
+        tag(" "),
+        take_while1(|c: u8| c.is_ascii_graphic()),
+        tag(" "),
+        http_version,
+        line_ending,
+    ))(input)?;
+
+    let line = RequestLine {
+        method: offsets(input, method),
+        target: offsets(input, target),
+        version: offsets(input, version),
+    };
+
+    Ok((rest, line))
+}
+
+/// Parses header fields up to and including the empty line that ends them. The same syntax is
+/// used for the trailer section of a chunked body.
+pub fn field_section(input: &[u8]) -> IResult<&[u8], Vec<HeaderField>> {
+    let (rest, (fields, _)) = many_till(field_line, line_ending)(input)?;
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| (offsets(input, name), offsets(input, value)))
+        .collect();
+
+    Ok((rest, fields))
+}
+
+/// Parses the line that starts each chunk of a chunked body, returning the chunk size. Chunk
+/// extensions are ignored.
+pub fn chunk_size(input: &[u8]) -> IResult<&[u8], usize> {
+    terminated(
+        map_res(take_while1(|c: u8| c.is_ascii_hexdigit()), |digits| {
+            // hex digits are always valid UTF-8
+            usize::from_str_radix(std::str::from_utf8(digits).unwrap(), 16)
+        }),
+        tuple((take_while(|c| c != b'\r' && c != b'\n'), line_ending)),
+    )(input)
+}
+
+/// Parses the line ending that follows the data of each chunk.
+pub fn chunk_end(input: &[u8]) -> IResult<&[u8], ()> {
+    value((), line_ending)(input)
+}
+
+/// Parses a line ending. Bare LF is accepted as well as CRLF, as RFC 9112 section 2.2 allows.
+pub fn line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    alt((tag("\r\n"), tag("\n")))(input)
+}
+
+fn field_line(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (rest, (name, _, _, value, _)) = tuple((
+        take_while1(is_tchar),
+        tag(":"),
+        take_while(is_ows),
+        take_while(|c: u8| c == b'\t' || (c >= b' ' && c != 0x7f)),
+        line_ending,
+    ))(input)?;
+
+    let trailing_ows = value.iter().rev().take_while(|c| is_ows(**c)).count();
+    Ok((rest, (name, &value[..value.len() - trailing_ows])))
+}
+
+fn http_version(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let digit = || take_while_m_n(1, 1, |c: u8| c.is_ascii_digit());
+    recognize(tuple((tag("HTTP/"), digit(), tag("."), digit())))(input)
+}
+
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn is_ows(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+fn offsets(input: &[u8], part: &[u8]) -> Range<usize> {
+    let start = part.as_ptr() as usize - input.as_ptr() as usize;
+    start..start + part.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_line_ok() {
+        let input = b"GET / HTTP/1.1\r\n";
+        let (rest, line) = request_line(input).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(&input[line.method], b"GET");
+        assert_eq!(&input[line.target], b"/");
+        assert_eq!(&input[line.version], b"HTTP/1.1");
+    }
+
+    #[test]
+    fn parse_request_line_no_sp() {
+        match request_line(b"GET\r\n") {
+            Err(nom::Err::Error(_)) => {}
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_request_line_incomplete() {
+        match request_line(b"GET /index.ht") {
+            Err(nom::Err::Incomplete(_)) => {}
+            other => panic!("expected incomplete, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_header_ok() {
+        let input = b"Host: localhost:4221 \r\nX-Empty:\r\n\r\nbody";
+        let (rest, fields) = field_section(input).unwrap();
+        assert_eq!(rest, b"body");
+        assert_eq!(fields.len(), 2);
+        assert_eq!(&input[fields[0].0.clone()], b"Host");
+        assert_eq!(&input[fields[0].1.clone()], b"localhost:4221");
+        assert_eq!(&input[fields[1].0.clone()], b"X-Empty");
+        assert_eq!(&input[fields[1].1.clone()], b"");
+    }
+
+    #[test]
+    fn parse_header_non_utf8_value() {
+        let input = b"X-Name: caf\xe9\r\n\r\n";
+        let (_, fields) = field_section(input).unwrap();
+        assert_eq!(&input[fields[0].1.clone()], b"caf\xe9");
+    }
+
+    #[test]
+    fn parse_header_obs_fold() {
+        assert!(matches!(
+            field_section(b"X-Folded: a\r\n b\r\n\r\n"),
+            Err(nom::Err::Error(_))
+        ));
+    }
+
+    #[test]
+    fn parse_chunk_size() {
+        assert_eq!(chunk_size(b"1a;name=value\r\n"), Ok((&b""[..], 26)));
+        assert!(matches!(chunk_size(b"1a"), Err(nom::Err::Incomplete(_))));
+        assert!(matches!(
+            chunk_size(b"fffffffffffffffffffff\r\n"),
+            Err(nom::Err::Error(_))
+        ));
+    }
+}
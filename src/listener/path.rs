@@ -0,0 +1,257 @@
+//! Mapping request targets to paths under the root directory, without letting them escape it.
+
+use crate::{listener::HttpResponse, options::SymlinkPolicy};
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+use thiserror::Error;
+use tokio::fs;
+
+#[derive(Error, Debug)]
+pub enum PathError {
+    #[error("invalid percent-encoding in path")]
+    InvalidEncoding,
+    #[error("path contains a NUL byte")]
+    NulByte,
+    #[error("path is outside the served directory")]
+    OutsideRoot,
+    #[error("path goes through a symbolic link")]
+    Symlink,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl PathError {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            Self::InvalidEncoding | Self::NulByte => {
+                HttpResponse::error(400, "Bad Request", self.to_string())
+            }
+            Self::OutsideRoot | Self::Symlink => {
+                HttpResponse::error(403, "Forbidden", self.to_string())
+            }
+            Self::Io(_) => HttpResponse::status(500, "Internal Server Error"),
+        }
+    }
+}
+
+/// Resolves `target`, the part of a request target naming a file under `root`, to a path.
+/// `root` has to be canonical already.
+///
+/// The target is percent-decoded and its dot segments removed as in RFC 3986 section 5.2.4,
+/// except that a `..` going above the root is an error instead of being dropped. The path is
+/// then checked against `symlinks` using the file system, which the file it names doesn't
+/// have to exist in yet.
+pub async fn resolve(
+    root: &Path,
+    target: &str,
+    symlinks: SymlinkPolicy,
+) -> Result<PathBuf, PathError> {
+    let target = target.split(['?', '#']).next().unwrap();
+    let decoded = percent_decode(target).ok_or(PathError::InvalidEncoding)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| PathError::InvalidEncoding)?;
+    if decoded.contains('\0') {
+        return Err(PathError::NulByte);
+    }
+
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or(PathError::OutsideRoot)?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let path = segments
+        .iter()
+        .fold(root.to_path_buf(), |path, segment| path.join(segment));
+
+    // the segments can't contain separators, but check anyway that joining them only ever
+    // added normal components
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|_| PathError::OutsideRoot)?;
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(PathError::OutsideRoot);
+    }
+
+    match symlinks {
+        SymlinkPolicy::Follow => {}
+        SymlinkPolicy::Deny => check_no_symlinks(root, relative).await?,
+        SymlinkPolicy::WithinRoot => check_within_root(root, &path).await?,
+    }
+
+    Ok(path)
+}
+
+/// Fails if any existing component of `relative`, below `root`, is a symbolic link.
+async fn check_no_symlinks(root: &Path, relative: &Path) -> Result<(), PathError> {
+    let mut path = root.to_path_buf();
+
+    for component in relative.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Err(PathError::Symlink),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails if `path` leads outside `root` once symbolic links are resolved. Components that
+/// don't exist yet can't be links, but a link whose target doesn't exist can't be checked, so
+/// it's rejected.
+async fn check_within_root(root: &Path, path: &Path) -> Result<(), PathError> {
+    for ancestor in path.ancestors() {
+        match fs::canonicalize(ancestor).await {
+            Ok(canonical) if canonical.starts_with(root) => return Ok(()),
+            Ok(_) => return Err(PathError::OutsideRoot),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if fs::symlink_metadata(ancestor).await.is_ok() {
+                    return Err(PathError::Symlink);
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Err(PathError::OutsideRoot)
+}
+
+/// Decodes `%XX` escapes. Returns `None` if a `%` isn't followed by two hex digits.
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = input.bytes();
+    let mut decoded = Vec::with_capacity(input.len());
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = (bytes.next()? as char).to_digit(16)?;
+            let low = (bytes.next()? as char).to_digit(16)?;
+            decoded.push((high * 16 + low) as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::listener::testing::TempDir;
+    use std::os::unix::fs::symlink;
+
+    /// A served directory with a file, a subdirectory and links, next to a file that mustn't
+    /// be reachable.
+    struct Fixture {
+        _dir: TempDir,
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(name);
+            let root = dir.path().join("root");
+            dir.write("root/file", "file");
+            dir.write("root/sub/file", "sub");
+            dir.write("outside/secret", "secret");
+            symlink(dir.path().join("outside"), root.join("out")).unwrap();
+            symlink(root.join("sub"), root.join("inner")).unwrap();
+            symlink(dir.path().join("missing"), root.join("dangling")).unwrap();
+
+            Self { _dir: dir, root }
+        }
+
+        async fn resolve(&self, target: &str, symlinks: SymlinkPolicy) -> Result<PathBuf, u16> {
+            resolve(&self.root, target, symlinks)
+                .await
+                .map_err(|err| err.to_response().status_code)
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_paths_inside_root() {
+        let fixture = Fixture::new("inside");
+        let root = &fixture.root;
+        let cases = [
+            ("file", root.join("file")),
+            ("", root.clone()),
+            ("sub/./file", root.join("sub/file")),
+            ("sub/../file", root.join("file")),
+            ("a/b/../../sub//file", root.join("sub/file")),
+            ("/etc/passwd", root.join("etc/passwd")),
+            ("new%20file.txt", root.join("new file.txt")),
+            ("caf%C3%A9", root.join("café")),
+            ("%252e%252e/file", root.join("%2e%2e/file")),
+            ("..%5c..%5cfile", root.join("..\\..\\file")),
+            ("file?download=1", root.join("file")),
+            ("inner/file", root.join("inner/file")),
+            ("missing/dir/file", root.join("missing/dir/file")),
+        ];
+
+        for (target, expected) in cases {
+            let resolved = fixture.resolve(target, SymlinkPolicy::WithinRoot).await;
+            assert_eq!(resolved, Ok(expected), "{:?}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_hostile_paths() {
+        let fixture = Fixture::new("hostile");
+        let cases = [
+            ("..", 403),
+            ("../outside/secret", 403),
+            ("sub/../../outside/secret", 403),
+            ("a/../../outside/secret", 403),
+            ("%2e%2e/outside/secret", 403),
+            ("%2E%2E%2Foutside%2Fsecret", 403),
+            ("..%2f..%2fetc%2fpasswd", 403),
+            ("sub/%2e%2e/%2e%2e/outside/secret", 403),
+            (".%2e/outside/secret", 403),
+            ("out/secret", 403),
+            ("out", 403),
+            ("dangling", 403),
+            ("dangling/file", 403),
+            ("file%00.txt", 400),
+            ("%zz", 400),
+            ("%2", 400),
+            ("%c0%ae%c0%ae/outside/secret", 400),
+        ];
+
+        for (target, status) in cases {
+            let resolved = fixture.resolve(target, SymlinkPolicy::WithinRoot).await;
+            assert_eq!(resolved, Err(status), "{:?}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_symlink_policy() {
+        let fixture = Fixture::new("symlinks");
+        let root = &fixture.root;
+
+        let resolved = fixture.resolve("out/secret", SymlinkPolicy::Follow).await;
+        assert_eq!(resolved, Ok(root.join("out/secret")));
+        let resolved = fixture
+            .resolve("../outside/secret", SymlinkPolicy::Follow)
+            .await;
+        assert_eq!(resolved, Err(403));
+
+        for target in ["inner/file", "out/secret", "dangling"] {
+            let resolved = fixture.resolve(target, SymlinkPolicy::Deny).await;
+            assert_eq!(resolved, Err(403), "{:?}", target);
+        }
+        let resolved = fixture.resolve("sub/new", SymlinkPolicy::Deny).await;
+        assert_eq!(resolved, Ok(root.join("sub/new")));
+    }
+}
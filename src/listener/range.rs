@@ -0,0 +1,276 @@
+//! Byte range requests (RFC 9110 section 14), for fetching parts of a file.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    ops::Range,
+};
+
+/// Most ranges served in one response. Requests for more get the whole file instead, so a
+/// client can't make the server do a lot of work for every byte it sends.
+const MAX_RANGES: usize = 64;
+
+/// What to respond with, given the `Range` field of a request.
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// The whole file, as if there was no range.
+    Full,
+    /// These ranges, in order.
+    Partial(Vec<Range<u64>>),
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+}
+
+/// Interprets a `Range` field for a file of `len` bytes. A field that can't be parsed or
+/// uses a unit other than bytes is ignored, as RFC 9110 allows. Overlapping ranges are
+/// merged, and ranges past the end of the file are dropped.
+pub fn parse(value: &str, len: u64) -> Ranges {
+    let specs = match value.split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match parse_spec(spec, len) {
+            Some(Some(range)) => ranges.push(range),
+            Some(None) => {}
+            None => return Ranges::Full,
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    let overlapping = ranges.iter().enumerate().any(|(i, a)| {
+        ranges[i + 1..]
+            .iter()
+            .any(|b| a.start < b.end && b.start < a.end)
+    });
+    if overlapping {
+        ranges = merge(ranges);
+    }
+
+    Ranges::Partial(ranges)
+}
+
+/// Parses one range spec, `first-last`, `first-` or `-suffix`. Returns `None` if it's
+/// malformed, and `Some(None)` if it's valid but doesn't overlap the file.
+fn parse_spec(spec: &str, len: u64) -> Option<Option<Range<u64>>> {
+    let (first, last) = spec.split_once('-')?;
+    let number = |s: &str| -> Option<u64> {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        // too many digits to fit is still a valid position, just past any file
+        Some(s.parse().unwrap_or(u64::MAX))
+    };
+
+    if first.is_empty() {
+        let suffix = number(last)?;
+        if suffix == 0 || len == 0 {
+            return Some(None);
+        }
+        return Some(Some(len.saturating_sub(suffix)..len));
+    }
+
+    let first = number(first)?;
+    let end = match last {
+        "" => len,
+        last => {
+            let last = number(last)?;
+            if last < first {
+                return None;
+            }
+            last.saturating_add(1).min(len)
+        }
+    };
+
+    Some((first < len).then_some(first..end))
+}
+
+/// Sorts ranges and combines those that overlap or are adjacent.
+fn merge(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+/// The `Content-Range` value for `range` of a file of `len` bytes.
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// Parses the `Content-Range` field of a request that writes part of a file, `bytes
+/// first-last/complete` where the complete length may be `*` for unknown. Returns the range
+/// written, or `None` if the value is malformed or inconsistent.
+pub fn parse_content_range(value: &str) -> Option<Range<u64>> {
+    let (unit, rest) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let (range, complete) = rest.trim().split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let number = |s: &str| -> Option<u64> {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    let (first, last) = (number(first)?, number(last)?);
+    if last < first {
+        return None;
+    }
+    if complete != "*" && number(complete)? <= last {
+        return None;
+    }
+
+    Some(first..last.checked_add(1)?)
+}
+
+/// The framing of a `multipart/byteranges` body (RFC 9110 section 14.6), which the data of
+/// each range goes in between.
+pub struct Multipart {
+    /// The type of the whole body, which names the boundary.
+    pub content_type: String,
+    /// What comes before the data of each range.
+    pub heads: Vec<String>,
+    /// What comes after the data of the last range.
+    pub tail: String,
+}
+
+impl Multipart {
+    /// Frames `ranges` of a file of `len` bytes whose type is `content_type`.
+    pub fn new(content_type: &str, len: u64, ranges: &[Range<u64>]) -> Self {
+        let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+        let heads = ranges
+            .iter()
+            .map(|range| {
+                format!(
+                    "\r\n--{}\r\ncontent-type: {}\r\ncontent-range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    content_range(range, len)
+                )
+            })
+            .collect();
+
+        Self {
+            content_type: format!("multipart/byteranges; boundary={}", boundary),
+            heads,
+            tail: format!("\r\n--{}--\r\n", boundary),
+        }
+    }
+
+    /// The length of the whole body, framing and data of `ranges` included.
+    pub fn len(&self, ranges: &[Range<u64>]) -> u64 {
+        let framing = self.heads.iter().map(String::len).sum::<usize>() + self.tail.len();
+        let data: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+        framing as u64 + data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn parse_ranges() {
+        let cases = [
+            ("bytes=0-499", Ranges::Partial(vec![0..500])),
+            ("bytes=500-999", Ranges::Partial(vec![500..1000])),
+            ("bytes=500-", Ranges::Partial(vec![500..1000])),
+            ("bytes=-200", Ranges::Partial(vec![800..1000])),
+            ("bytes=-2000", Ranges::Partial(vec![0..1000])),
+            ("bytes=900-2000", Ranges::Partial(vec![900..1000])),
+            ("bytes=0-0,-1", Ranges::Partial(vec![0..1, 999..1000])),
+            ("Bytes = 1-2 , 5-6", Ranges::Partial(vec![1..3, 5..7])),
+            (
+                "bytes=500-600,0-99",
+                Ranges::Partial(vec![500..601, 0..100]),
+            ),
+            ("bytes=0-99,50-149,150-199", Ranges::Partial(vec![0..200])),
+            ("bytes=0-99,2000-3000", Ranges::Partial(vec![0..100])),
+            ("bytes=1000-", Ranges::Unsatisfiable),
+            ("bytes=1000-1999,-0", Ranges::Unsatisfiable),
+            ("bytes=99999999999999999999999-", Ranges::Unsatisfiable),
+            ("bytes=", Ranges::Unsatisfiable),
+            ("bytes=5-1", Ranges::Full),
+            ("bytes=a-b", Ranges::Full),
+            ("bytes=0-1,x", Ranges::Full),
+            ("bytes=--1", Ranges::Full),
+            ("items=0-5", Ranges::Full),
+            ("0-5", Ranges::Full),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse(value, 1000), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn parse_ranges_of_empty_file() {
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignore_too_many_ranges() {
+        let specs = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2));
+        let value = format!("bytes={}", specs.collect::<Vec<_>>().join(","));
+        assert_eq!(parse(&value, 1000), Ranges::Full);
+    }
+
+    #[test]
+    fn parse_content_ranges() {
+        assert_eq!(parse_content_range("bytes 0-4/*"), Some(0..5));
+        assert_eq!(parse_content_range("bytes 10-19/20"), Some(10..20));
+        assert_eq!(parse_content_range(" Bytes 3-3/* "), Some(3..4));
+        assert_eq!(parse_content_range("bytes 10-19/19"), None);
+        assert_eq!(parse_content_range("bytes 5-4/*"), None);
+        assert_eq!(parse_content_range("bytes 0-/*"), None);
+        assert_eq!(parse_content_range("bytes */20"), None);
+        assert_eq!(parse_content_range("items 0-4/*"), None);
+        assert_eq!(parse_content_range("bytes 0-4"), None);
+    }
+
+    #[test]
+    fn frame_multipart_body() {
+        let ranges = [0..3, 7..10];
+        let multipart = Multipart::new("text/plain", 10, &ranges);
+
+        let boundary = multipart
+            .content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            concat!(
+                "\r\n--{0}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-2/10\r\n\r\nabc",
+                "\r\n--{0}\r\ncontent-type: text/plain\r\ncontent-range: bytes 7-9/10\r\n\r\nhij",
+                "\r\n--{0}--\r\n"
+            ),
+            boundary
+        );
+        let body = format!(
+            "{}abc{}hij{}",
+            multipart.heads[0], multipart.heads[1], multipart.tail
+        );
+        assert_eq!(body, expected);
+        assert_eq!(multipart.len(&ranges), expected.len() as u64);
+    }
+}
@@ -1,175 +1,615 @@
-use std::{collections::HashMap, fmt, io, num::ParseIntError, str::FromStr, vec};
+use crate::{
+    listener::{compression, headers::Headers, parser, HttpResponse},
+    options::{RequestLimits, Timeouts},
+};
+use bytes::{Bytes, BytesMut};
+use nom::IResult;
+use std::{io, time::Duration};
 use thiserror::Error;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+/// How much spare capacity to make room for in the read buffer before each read.
+const READ_SIZE: usize = 8 * 1024;
 
 #[derive(Debug)]
 pub struct HttpRequest {
-    pub method: String,
-    pub target: String,
-    pub version: String,
-    pub headers: HashMap<String, Vec<String>>,
-    pub body: Vec<u8>,
+    method: Bytes,
+    target: Bytes,
+    version: Bytes,
+    pub headers: Headers,
+    pub body: RequestBody,
+}
+
+impl HttpRequest {
+    pub fn method(&self) -> &str {
+        ascii(&self.method)
+    }
+
+    pub fn target(&self) -> &str {
+        ascii(&self.target)
+    }
+
+    pub fn version(&self) -> &str {
+        ascii(&self.version)
+    }
+
+    /// Whether the client wants the connection kept open after this request. HTTP/1.1
+    /// connections persist unless closed explicitly, while HTTP/1.0 ones must opt in.
+    pub fn keeps_alive(&self) -> bool {
+        let mut options = self.headers.get_list("connection");
+        if self.version() == "HTTP/1.0" {
+            options.any(|option| option.eq_ignore_ascii_case("keep-alive"))
+        } else {
+            !options.any(|option| option.eq_ignore_ascii_case("close"))
+        }
+    }
+}
+
+/// The body of a request. Bodies are read from the connection as the handler asks for them,
+/// unless they had to be read in full already, to decode them.
+#[derive(Debug)]
+pub enum RequestBody {
+    Full(Bytes),
+    /// Pieces passed on by the connection as they arrive, ending with an error if the rest
+    /// couldn't be read.
+    Stream(mpsc::Receiver<Result<Bytes, HttpRequestError>>),
+}
+
+impl RequestBody {
+    /// The next piece of the body, or `None` once all of it has been read.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, HttpRequestError> {
+        match self {
+            Self::Full(bytes) if bytes.is_empty() => Ok(None),
+            Self::Full(bytes) => Ok(Some(std::mem::take(bytes))),
+            Self::Stream(pieces) => pieces.recv().await.transpose(),
+        }
+    }
+
+    /// Reads the rest of the body into memory. It's never more than the maximum body size.
+    pub async fn read_to_end(mut self) -> Result<Bytes, HttpRequestError> {
+        if let Self::Full(bytes) = self {
+            return Ok(bytes);
+        }
+
+        let mut body = BytesMut::new();
+        while let Some(piece) = self.chunk().await? {
+            body.extend_from_slice(&piece);
+        }
+
+        Ok(body.freeze())
+    }
+}
+
+impl Default for RequestBody {
+    fn default() -> Self {
+        Self::Full(Bytes::new())
+    }
 }
 
+#[cfg(test)]
+impl<T: AsRef<[u8]>> PartialEq<T> for RequestBody {
+    fn eq(&self, other: &T) -> bool {
+        matches!(self, Self::Full(bytes) if bytes == other.as_ref())
+    }
+}
+
+/// Views request line elements as text. The parser only accepts ASCII for all of them.
+fn ascii(bytes: &Bytes) -> &str {
+    std::str::from_utf8(bytes).expect("request line elements are ASCII")
+}
+
+/// Reasons a request could not be read or its body decoded. Each maps to the status code of
+/// the response sent instead of handling the request.
 #[derive(Error, Debug)]
-pub struct HttpRequestError(Option<String>);
+pub enum HttpRequestError {
+    #[error("malformed request line")]
+    MalformedRequestLine,
+    #[error("malformed header field")]
+    BadHeader,
+    #[error("invalid content-length: {0:?}")]
+    InvalidContentLength(String),
+    #[error("invalid message framing: {0}")]
+    InvalidFraming(&'static str),
+    #[error("unsupported transfer coding: {0:?}")]
+    UnsupportedTransferCoding(String),
+    #[error("unsupported HTTP version: {0}")]
+    UnsupportedVersion(String),
+    #[error("request line exceeds the size limit")]
+    UriTooLong,
+    #[error("header section exceeds the size limit")]
+    HeaderFieldsTooLarge,
+    #[error("request body exceeds the size limit")]
+    PayloadTooLarge,
+    #[error("unsupported content coding: {0:?}")]
+    UnsupportedContentCoding(String),
+    #[error("request body isn't valid {0} data")]
+    CorruptContent(&'static str),
+    #[error("decoded request body exceeds the size limit")]
+    DecodedPayloadTooLarge,
+    #[error("connection closed in the middle of a request")]
+    Incomplete,
+    #[error("timed out waiting for the request")]
+    Timeout,
+    #[error("I/O error: {0}")]
+    Io(io::Error),
+}
+
+impl HttpRequestError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::MalformedRequestLine
+            | Self::BadHeader
+            | Self::InvalidContentLength(_)
+            | Self::InvalidFraming(_)
+            | Self::CorruptContent(_)
+            | Self::Incomplete
+            | Self::Io(_) => 400,
+            Self::Timeout => 408,
+            Self::PayloadTooLarge | Self::DecodedPayloadTooLarge => 413,
+            Self::UriTooLong => 414,
+            Self::UnsupportedContentCoding(_) => 415,
+            Self::HeaderFieldsTooLarge => 431,
+            Self::UnsupportedTransferCoding(_) => 501,
+            Self::UnsupportedVersion(_) => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self.status_code() {
+            408 => "Request Timeout",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            431 => "Request Header Fields Too Large",
+            501 => "Not Implemented",
+            505 => "HTTP Version Not Supported",
+            _ => "Bad Request",
+        }
+    }
+
+    /// Whether the rest of the connection can't be split into requests anymore after this
+    /// error. Only decoding errors leave it intact, as they happen once the body has been read.
+    pub fn breaks_framing(&self) -> bool {
+        !matches!(
+            self,
+            Self::UnsupportedContentCoding(_)
+                | Self::CorruptContent(_)
+                | Self::DecodedPayloadTooLarge
+        )
+    }
+
+    /// A response describing this error, with the error message as a plain text body. It
+    /// closes the connection if the error broke the framing of requests on it.
+    pub fn to_response(&self) -> HttpResponse {
+        let response = HttpResponse::error(self.status_code(), self.reason(), self.to_string());
+        let response = match self {
+            // tells the client which codings it can use instead (RFC 9110 section 15.5.16)
+            Self::UnsupportedContentCoding(_) => {
+                response.with_header("accept-encoding", compression::SUPPORTED_CODINGS)
+            }
+            _ => response,
+        };
+
+        if self.breaks_framing() {
+            response.with_header("connection", "close")
+        } else {
+            response
+        }
+    }
+}
 
 impl From<io::Error> for HttpRequestError {
     fn from(value: io::Error) -> Self {
-        println!("I/O error while parsing request: {}", value);
-        Self(None)
+        match value.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(value),
+        }
     }
 }
 
-impl From<ParseIntError> for HttpRequestError {
-    fn from(value: ParseIntError) -> Self {
-        println!("error parsing value as integer: {}", value);
-        Self(None)
-    }
+/// Reads requests from a connection into a single buffer. Request heads are parsed in place,
+/// and the resulting request refers to slices of that buffer instead of copying them.
+pub struct RequestReader<R> {
+    reader: R,
+    buffer: BytesMut,
+    limits: RequestLimits,
+    timeouts: Timeouts,
+    deadline: Deadline,
+    body: BodyState,
 }
 
-pub async fn read<R: AsyncBufRead + Unpin>(
-    reader: &mut R,
-) -> Result<Option<HttpRequest>, HttpRequestError> {
-    let mut buffer = String::new();
-    let bytes_read = reader.read_line(&mut buffer).await?;
+/// When the read in progress has to complete by.
+#[derive(Clone, Copy, Debug)]
+enum Deadline {
+    None,
+    At(Instant),
+    /// Data has to keep arriving at the minimum body rate, once the grace period is over.
+    BodyRate {
+        started: Instant,
+        received: u64,
+    },
+}
+
+/// How much of the last request's body is left to read.
+#[derive(Clone, Copy, Debug)]
+enum BodyState {
+    /// There's no body, or all of it has been read.
+    Done,
+    /// This many bytes of a body with a known length.
+    Length(usize),
+    /// A chunked body, with this many bytes left of the current chunk and this many received
+    /// in all chunks so far.
+    Chunked { in_chunk: usize, received: usize },
+}
 
-    if bytes_read == 0 {
-        return Ok(None);
+impl<R: AsyncRead + Unpin> RequestReader<R> {
+    pub fn new(reader: R, limits: RequestLimits, timeouts: Timeouts) -> Self {
+        Self {
+            reader,
+            buffer: BytesMut::with_capacity(READ_SIZE),
+            limits,
+            timeouts,
+            deadline: Deadline::None,
+            body: BodyState::Done,
+        }
     }
 
-    let request_line = buffer.trim_end().parse::<RequestLine>()?;
-    let mut headers = HashMap::<String, Vec<String>>::new();
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
 
-    loop {
-        buffer.clear();
-        reader.read_line(&mut buffer).await?;
+    /// Waits until the client starts sending a request. Returns `false` if the connection was
+    /// closed instead, and fails with `TimedOut` if it stays idle for longer than allowed.
+    pub async fn wait_for_request(&mut self) -> io::Result<bool> {
+        if !self.buffer.is_empty() {
+            return Ok(true);
+        }
 
-        if buffer.trim().is_empty() {
-            break;
+        self.deadline = Deadline::At(Instant::now() + self.timeouts.idle);
+        Ok(self.fill().await? > 0)
+    }
+
+    /// Reads the next request along with its whole body, or returns `None` if the connection
+    /// was closed before one started.
+    #[cfg(test)]
+    pub async fn read(&mut self) -> Result<Option<HttpRequest>, HttpRequestError> {
+        let Some(mut request) = self.read_head().await? else {
+            return Ok(None);
+        };
+
+        let mut body = BytesMut::new();
+        while let Some(piece) = self.read_body().await? {
+            body.extend_from_slice(&piece);
         }
 
-        let header = buffer.parse::<HttpHeader>()?;
-        let values = headers.entry(header.key).or_default();
-        values.push(header.value);
+        request.body = RequestBody::Full(body.freeze());
+        Ok(Some(request))
     }
 
-    let body: Vec<u8> = if request_line.method != "GET" {
-        match headers.get("content-length") {
-            Some(values) => {
-                let length = values.last().unwrap().parse::<usize>()?;
-                let mut body = vec![0u8; length];
-                reader.read_exact(&mut body).await?;
-                body
+    /// Reads the head of the next request, or returns `None` if the connection was closed
+    /// before one started. The body is left on the connection, to be read with `read_body`
+    /// before the next request.
+    pub async fn read_head(&mut self) -> Result<Option<HttpRequest>, HttpRequestError> {
+        self.deadline = Deadline::At(Instant::now() + self.timeouts.headers);
+        if self.buffer.is_empty() && self.fill().await? == 0 {
+            return Ok(None);
+        }
+
+        let (line, line_bytes) = self
+            .parse(
+                parser::request_line,
+                self.limits.max_request_line,
+                HttpRequestError::UriTooLong,
+                HttpRequestError::MalformedRequestLine,
+            )
+            .await?;
+
+        // any HTTP/1.x minor version is processed as HTTP/1.1, per RFC 9110 section 6.2
+        let version = line_bytes.slice(line.version);
+        if !version.starts_with(b"HTTP/1.") {
+            return Err(HttpRequestError::UnsupportedVersion(
+                ascii(&version).to_string(),
+            ));
+        }
+
+        let (fields, field_bytes) = self
+            .parse(
+                parser::field_section,
+                self.limits.max_header_size,
+                HttpRequestError::HeaderFieldsTooLarge,
+                HttpRequestError::BadHeader,
+            )
+            .await?;
+
+        if fields.len() > self.limits.max_headers {
+            return Err(HttpRequestError::HeaderFieldsTooLarge);
+        }
+
+        let fields = fields
+            .into_iter()
+            .map(|(name, value)| (field_bytes.slice(name), field_bytes.slice(value)))
+            .collect();
+
+        let request = HttpRequest {
+            method: line_bytes.slice(line.method),
+            target: line_bytes.slice(line.target),
+            version,
+            headers: Headers::new(fields),
+            body: RequestBody::default(),
+        };
+
+        if self.timeouts.min_body_rate > 0 {
+            self.deadline = Deadline::BodyRate {
+                started: Instant::now(),
+                received: 0,
+            };
+        } else {
+            self.deadline = Deadline::None;
+        }
+
+        self.body = match BodyFraming::of(&request)? {
+            BodyFraming::Chunked => BodyState::Chunked {
+                in_chunk: 0,
+                received: 0,
+            },
+            BodyFraming::Length(length) if length > self.limits.max_body_size => {
+                return Err(HttpRequestError::PayloadTooLarge);
             }
-            None => {
-                let mut body = Vec::new();
-                reader.read_to_end(&mut body).await?;
-                body
+            BodyFraming::Length(length) => BodyState::Length(length),
+            BodyFraming::Empty => BodyState::Done,
+        };
+
+        Ok(Some(request))
+    }
+
+    /// Whether the body of the last request read has bytes left to read.
+    pub fn has_body(&self) -> bool {
+        !matches!(self.body, BodyState::Done | BodyState::Length(0))
+    }
+
+    /// Leaves `paused` out of the time the minimum body rate is measured over, for when the
+    /// body wasn't read because what it's passed on to wasn't ready for more of it. That
+    /// isn't the client's doing.
+    pub fn pause_body_rate(&mut self, paused: Duration) {
+        if let Deadline::BodyRate { started, .. } = &mut self.deadline {
+            *started += paused;
+        }
+    }
+
+    /// Reads the next piece of the last request's body, of whatever size arrived, or returns
+    /// `None` once all of it has been read.
+    pub async fn read_body(&mut self) -> Result<Option<Bytes>, HttpRequestError> {
+        loop {
+            match self.body {
+                BodyState::Done | BodyState::Length(0) => {
+                    self.body = BodyState::Done;
+                    return Ok(None);
+                }
+                BodyState::Length(remaining) => {
+                    let piece = self.read_some(remaining).await?;
+                    self.body = BodyState::Length(remaining - piece.len());
+                    return Ok(Some(piece));
+                }
+                BodyState::Chunked {
+                    in_chunk: 0,
+                    received,
+                } => {
+                    let (size, _) = self
+                        .parse(
+                            parser::chunk_size,
+                            self.limits.max_header_size,
+                            HttpRequestError::InvalidFraming("chunk size line is too long"),
+                            HttpRequestError::InvalidFraming("malformed chunk size"),
+                        )
+                        .await?;
+
+                    if size == 0 {
+                        // trailer fields are discarded; none of the handlers have a use for them
+                        self.parse(
+                            parser::field_section,
+                            self.limits.max_header_size,
+                            HttpRequestError::HeaderFieldsTooLarge,
+                            HttpRequestError::BadHeader,
+                        )
+                        .await?;
+                        self.body = BodyState::Done;
+                        return Ok(None);
+                    }
+
+                    if size > self.limits.max_body_size - received {
+                        return Err(HttpRequestError::PayloadTooLarge);
+                    }
+
+                    self.body = BodyState::Chunked {
+                        in_chunk: size,
+                        received: received + size,
+                    };
+                }
+                BodyState::Chunked { in_chunk, received } => {
+                    let piece = self.read_some(in_chunk).await?;
+                    let in_chunk = in_chunk - piece.len();
+                    if in_chunk == 0 {
+                        let missing_line_ending = || {
+                            HttpRequestError::InvalidFraming(
+                                "chunk data not followed by a line ending",
+                            )
+                        };
+                        self.parse(
+                            parser::chunk_end,
+                            2,
+                            missing_line_ending(),
+                            missing_line_ending(),
+                        )
+                        .await?;
+                    }
+
+                    self.body = BodyState::Chunked { in_chunk, received };
+                    return Ok(Some(piece));
+                }
             }
         }
-    } else {
-        vec![]
-    };
+    }
 
-    Ok(Some(HttpRequest {
-        method: request_line.method,
-        target: request_line.target,
-        version: request_line.version,
-        headers,
-        body,
-    }))
-}
+    /// Runs `parser` over the buffer, reading more whenever it needs more input. On success,
+    /// removes the parsed bytes from the buffer and returns them along with the parser output.
+    /// Fails with `too_large` if the parsed element doesn't fit in `limit` bytes, or with
+    /// `malformed` if the parser rejects the input.
+    async fn parse<T>(
+        &mut self,
+        mut parser: impl FnMut(&[u8]) -> IResult<&[u8], T>,
+        limit: usize,
+        too_large: HttpRequestError,
+        malformed: HttpRequestError,
+    ) -> Result<(T, Bytes), HttpRequestError> {
+        loop {
+            match parser(&self.buffer) {
+                Ok((rest, output)) => {
+                    let length = self.buffer.len() - rest.len();
+                    if length > limit {
+                        return Err(too_large);
+                    }
 
-#[derive(Debug)]
-struct RequestLine {
-    method: String,
-    target: String,
-    version: String,
-}
-
-impl FromStr for RequestLine {
-    type Err = HttpRequestError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.split_once(' ') {
-            Some((method, rest)) => match rest.split_once(' ') {
-                Some((target, version)) => Ok(Self {
-                    method: method.to_string(),
-                    target: target.to_string(),
-                    version: version.to_string(),
-                }),
-                None => {
-                    println!("second SP not found in request line: {}", s);
-                    Err(HttpRequestError(None))
+                    return Ok((output, self.buffer.split_to(length).freeze()));
                 }
-            },
-            None => {
-                println!("first SP not found in request line: {}", s);
-                Err(HttpRequestError(None))
+                Err(nom::Err::Incomplete(_)) if self.buffer.len() >= limit => {
+                    return Err(too_large);
+                }
+                Err(nom::Err::Incomplete(_)) => {
+                    if self.fill().await? == 0 {
+                        return Err(HttpRequestError::Incomplete);
+                    }
+                }
+                Err(_) => return Err(malformed),
             }
         }
     }
-}
 
-impl fmt::Display for HttpRequestError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.0 {
-            Some(message) => write!(f, "HTTP 400 {}", message),
-            None => write!(f, "HTTP 400 Bad Request"),
+    /// Takes up to `limit` bytes from the buffer, reading more first if it's empty.
+    async fn read_some(&mut self, limit: usize) -> Result<Bytes, HttpRequestError> {
+        if self.buffer.is_empty() && self.fill().await? == 0 {
+            return Err(HttpRequestError::Incomplete);
         }
+
+        let length = limit.min(self.buffer.len());
+        Ok(self.buffer.split_to(length).freeze())
+    }
+
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buffer.reserve(READ_SIZE);
+
+        let deadline = match self.deadline {
+            Deadline::None => None,
+            Deadline::At(deadline) => Some(deadline),
+            Deadline::BodyRate { started, received } => {
+                let expected = received as f64 / self.timeouts.min_body_rate as f64;
+                Some(started + self.timeouts.body_grace + Duration::from_secs_f64(expected))
+            }
+        };
+
+        let read = self.reader.read_buf(&mut self.buffer);
+        let bytes_read = match deadline {
+            Some(deadline) => time::timeout_at(deadline, read)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+            None => read.await?,
+        };
+
+        if let Deadline::BodyRate { received, .. } = &mut self.deadline {
+            *received += bytes_read as u64;
+        }
+
+        Ok(bytes_read)
     }
 }
 
-struct HttpHeader {
-    key: String,
-    value: String,
+/// How the length of a request body is determined, per RFC 9112 section 6.3.
+#[derive(Debug, PartialEq)]
+enum BodyFraming {
+    Chunked,
+    Length(usize),
+    Empty,
 }
 
-impl FromStr for HttpHeader {
-    type Err = HttpRequestError;
+impl BodyFraming {
+    fn of(request: &HttpRequest) -> Result<Self, HttpRequestError> {
+        if request.headers.get("transfer-encoding").is_some() {
+            if request.version() == "HTTP/1.0" {
+                return Err(HttpRequestError::InvalidFraming(
+                    "transfer-encoding in an HTTP/1.0 request",
+                ));
+            }
+
+            // chunked must be the final coding, otherwise the body length can't be determined
+            let mut codings = request.headers.get_list("transfer-encoding");
+            return match codings.next_back() {
+                Some(coding) if coding.eq_ignore_ascii_case("chunked") => match codings.next() {
+                    Some(coding) => Err(HttpRequestError::UnsupportedTransferCoding(
+                        coding.to_string(),
+                    )),
+                    None => Ok(Self::Chunked),
+                },
+                _ => Err(HttpRequestError::InvalidFraming(
+                    "chunked is not the final transfer coding",
+                )),
+            };
+        }
+
+        // repeated values are only allowed if they're all the same, per RFC 9110 section 8.6
+        let mut length = None;
+        for value in request.headers.get_all("content-length") {
+            let text = String::from_utf8_lossy(value);
+            for element in text.split(',').map(|element| element.trim()) {
+                let invalid = || HttpRequestError::InvalidContentLength(text.to_string());
+                if element.is_empty() || !element.bytes().all(|c| c.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+
+                let element = element.parse::<usize>().map_err(|_| invalid())?;
+                if length.is_some_and(|length| length != element) {
+                    return Err(invalid());
+                }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.split_once(':') {
-            Some((key, value)) => Ok(Self {
-                key: key.to_lowercase(),
-                value: value.trim().to_string(),
-            }),
-            _ => {
-                println!(": not found in HTTP header line");
-                Err(HttpRequestError(None))
+                length = Some(element);
             }
         }
+
+        Ok(length.map_or(Self::Empty, Self::Length))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::io::BufReader;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncWriteExt, ReadBuf};
 
     use super::*;
 
-    #[test]
-    fn parse_request_line_ok() {
-        let line = "GET / HTTP/1.1".parse::<RequestLine>().unwrap();
-        assert_eq!(line.method, "GET");
-        assert_eq!(line.target, "/");
-        assert_eq!(line.version, "HTTP/1.1");
+    fn reader(source: &[u8]) -> RequestReader<&[u8]> {
+        RequestReader::new(source, RequestLimits::default(), Timeouts::default())
     }
 
-    #[test]
-    fn parse_request_line_no_sp() {
-        match "GET".parse::<RequestLine>() {
-            Err(_) => {}
-            other => panic!("expected parse error, got {:?}", other),
-        }
-    }
+    /// Yields its source one byte per read, to exercise parsing across partial reads.
+    struct Trickle<'a>(&'a [u8]);
 
-    #[test]
-    fn parse_header_ok() {
-        let HttpHeader { key, value } = "Host: localhost:4221".parse().unwrap();
-        assert_eq!(key, "host");
-        assert_eq!(value, "localhost:4221");
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some((first, rest)) = self.0.split_first() {
+                buf.put_slice(&[*first]);
+                self.0 = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
     }
 
     #[tokio::test]
@@ -181,33 +621,331 @@ mod tests {
             "\r\n"
         );
 
-        let mut reader = BufReader::new(request_str.as_bytes());
-        let request = super::read(&mut reader).await.unwrap().unwrap();
+        let request = reader(request_str.as_bytes())
+            .read()
+            .await
+            .unwrap()
+            .unwrap();
 
-        assert_eq!(request.method, "GET");
-        assert_eq!(request.target, "/index.html");
-        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.method(), "GET");
+        assert_eq!(request.target(), "/index.html");
+        assert_eq!(request.version(), "HTTP/1.1");
 
         let headers = request.headers;
-        assert_eq!(headers.len(), 2);
-        assert_eq!(
-            headers.get("host"),
-            Some(&vec!["localhost:4221".to_string()])
+        assert_eq!(headers.get("host"), Some(&b"localhost:4221"[..]));
+        assert_eq!(headers.get("user-agent"), Some(&b"curl/7.64.1"[..]));
+        assert_eq!(headers.get("accept"), None);
+
+        let body = request.body;
+        assert_eq!(body, b"");
+    }
+
+    #[tokio::test]
+    async fn parse_request_from_partial_reads() {
+        let request_str = concat!(
+            "POST /files/upload HTTP/1.1\r\n",
+            "Host: localhost:4221\r\n",
+            "Transfer-Encoding: chunked\r\n",
+            "\r\n",
+            "5\r\n",
+            "hello\r\n",
+            "0\r\n",
+            "\r\n",
+            "GET /echo/abc HTTP/1.1\r\n",
+            "\r\n",
         );
-        assert_eq!(
-            headers.get("user-agent"),
-            Some(&vec!["curl/7.64.1".to_string()])
+
+        let source = Trickle(request_str.as_bytes());
+        let mut reader = RequestReader::new(source, RequestLimits::default(), Timeouts::default());
+
+        let request = reader.read().await.unwrap().unwrap();
+        assert_eq!(request.target(), "/files/upload");
+        assert_eq!(request.headers.get("host"), Some(&b"localhost:4221"[..]));
+        assert_eq!(request.body, &b"hello"[..]);
+
+        let next = reader.read().await.unwrap().unwrap();
+        assert_eq!(next.target(), "/echo/abc");
+        assert!(reader.read().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn parse_chunked_body() {
+        let request_str = concat!(
+            "POST /files/upload HTTP/1.1\r\n",
+            "Host: localhost:4221\r\n",
+            "Transfer-Encoding: chunked\r\n",
+            "\r\n",
+            "5;name=value\r\n",
+            "hello\r\n",
+            "7\r\n",
+            ", world\r\n",
+            "0\r\n",
+            "Expires: never\r\n",
+            "\r\n",
+            "GET / HTTP/1.1\r\n",
+            "\r\n",
         );
 
-        let body = request.body;
-        assert_eq!(body.len(), 0);
+        let mut reader = reader(request_str.as_bytes());
+        let request = reader.read().await.unwrap().unwrap();
+        assert_eq!(request.body, &b"hello, world"[..]);
+
+        let next = reader.read().await.unwrap().unwrap();
+        assert_eq!(next.method(), "GET");
+        assert_eq!(next.target(), "/");
+    }
+
+    #[tokio::test]
+    async fn read_body_in_pieces() {
+        let request_str = concat!(
+            "POST /files/upload HTTP/1.1\r\n",
+            "Transfer-Encoding: chunked\r\n",
+            "\r\n",
+            "5\r\n",
+            "hello\r\n",
+            "7\r\n",
+            ", world\r\n",
+            "0\r\n",
+            "\r\n",
+            "GET / HTTP/1.1\r\n",
+            "\r\n",
+        );
+
+        let mut reader = reader(request_str.as_bytes());
+        let request = reader.read_head().await.unwrap().unwrap();
+        assert_eq!(request.target(), "/files/upload");
+        assert!(reader.has_body());
+
+        // pieces never cross chunk boundaries
+        assert_eq!(reader.read_body().await.unwrap().unwrap(), &b"hello"[..]);
+        assert_eq!(reader.read_body().await.unwrap().unwrap(), &b", world"[..]);
+        assert!(reader.read_body().await.unwrap().is_none());
+        assert!(!reader.has_body());
+
+        let next = reader.read_head().await.unwrap().unwrap();
+        assert_eq!(next.target(), "/");
+        assert!(!reader.has_body());
+    }
+
+    #[tokio::test]
+    async fn parse_chunked_body_in_http_1_0() {
+        let request_str = concat!(
+            "POST /files/upload HTTP/1.0\r\n",
+            "Transfer-Encoding: chunked\r\n",
+            "\r\n",
+            "0\r\n",
+            "\r\n",
+        );
+
+        assert!(reader(request_str.as_bytes()).read().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn parse_request_without_framing() {
+        let request_str = concat!(
+            "POST /files/empty HTTP/1.1\r\n",
+            "Host: localhost:4221\r\n",
+            "\r\n",
+            "GET / HTTP/1.1\r\n",
+            "\r\n",
+        );
+
+        let mut reader = reader(request_str.as_bytes());
+        let request = reader.read().await.unwrap().unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.body, b"");
+
+        let next = reader.read().await.unwrap().unwrap();
+        assert_eq!(next.method(), "GET");
+    }
+
+    #[tokio::test]
+    async fn reject_requests_over_limits() {
+        let limits = RequestLimits {
+            max_request_line: 32,
+            max_header_size: 64,
+            max_headers: 2,
+            max_body_size: 4,
+        };
+
+        let cases = [
+            ("GET /a/very/long/target/that/goes/on HTTP/1.1\r\n\r\n", 414),
+            ("GET / HTTP/1.1\r\nX-Long: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n", 431),
+            ("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", 431),
+            ("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", 413),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n", 413),
+        ];
+
+        for (request_str, status_code) in cases {
+            let mut reader =
+                RequestReader::new(request_str.as_bytes(), limits.clone(), Timeouts::default());
+            match reader.read().await {
+                Err(error) => assert_eq!(error.status_code(), status_code, "{}", request_str),
+                other => panic!("expected error for {:?}, got {:?}", request_str, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_malformed_requests() {
+        let cases = [
+            ("GET/ HTTP/1.1\r\n\r\n", 400),
+            ("GET / HTTP/2.0\r\n\r\n", 505),
+            ("GET / HTTP/1.1\r\nHost localhost\r\n\r\n", 400),
+            ("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello", 400),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+                400,
+            ),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", 400),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                501,
+            ),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                400,
+            ),
+            ("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel", 400),
+        ];
+
+        for (request_str, status_code) in cases {
+            match reader(request_str.as_bytes()).read().await {
+                Err(error) => assert_eq!(error.status_code(), status_code, "{}", request_str),
+                other => panic!("expected error for {:?}, got {:?}", request_str, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_repeated_content_length() {
+        let request_str = concat!(
+            "POST / HTTP/1.1\r\n",
+            "Content-Length: 5, 5\r\n",
+            "Content-Length: 5\r\n",
+            "\r\n",
+            "hello"
+        );
+
+        let request = reader(request_str.as_bytes())
+            .read()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body, &b"hello"[..]);
+    }
+
+    fn short_timeouts() -> Timeouts {
+        Timeouts {
+            idle: Duration::from_millis(50),
+            headers: Duration::from_millis(50),
+            min_body_rate: 1000,
+            body_grace: Duration::from_millis(50),
+            shutdown: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn time_out_idle_connection() {
+        let (_client, server) = tokio::io::duplex(64);
+        let mut reader = RequestReader::new(server, RequestLimits::default(), short_timeouts());
+
+        let error = reader.wait_for_request().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn time_out_slow_headers() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = RequestReader::new(server, RequestLimits::default(), short_timeouts());
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: loc")
+            .await
+            .unwrap();
+        assert!(reader.wait_for_request().await.unwrap());
+
+        let error = reader.read().await.unwrap_err();
+        assert_eq!(error.status_code(), 408);
+    }
+
+    #[tokio::test]
+    async fn time_out_slow_body() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = RequestReader::new(server, RequestLimits::default(), short_timeouts());
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n0123456789")
+            .await
+            .unwrap();
+
+        let error = reader.read().await.unwrap_err();
+        assert_eq!(error.status_code(), 408);
+    }
+
+    #[tokio::test]
+    async fn pause_body_rate() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = RequestReader::new(server, RequestLimits::default(), short_timeouts());
+        tokio::spawn(async move {
+            let body = [b'x'; 1000];
+            client
+                .write_all(b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n")
+                .await
+                .unwrap();
+            client.write_all(&body).await.unwrap();
+        });
+
+        reader.read_head().await.unwrap().unwrap();
+        let mut received = reader.read_body().await.unwrap().unwrap().len();
+        // longer than what's been received takes at the minimum rate, as if the handler was busy
+        let paused = Duration::from_millis(300);
+        time::sleep(paused).await;
+        reader.pause_body_rate(paused);
+        while let Some(piece) = reader.read_body().await.unwrap() {
+            received += piece.len();
+        }
+        assert_eq!(received, 1000);
     }
 
     #[tokio::test]
     async fn parse_empty_request() {
-        let source: Vec<u8> = vec![];
-        let mut reader = BufReader::new(source.as_slice());
-        let request = super::read(&mut reader).await.unwrap();
+        let request = reader(&[]).read().await.unwrap();
         assert!(request.is_none());
     }
+
+    /// Run with `cargo test --release bench -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_parse_requests() {
+        let request_str = concat!(
+            "GET /files/some/fairly/long/path/to/an/artifact.tar.gz HTTP/1.1\r\n",
+            "Host: localhost:4221\r\n",
+            "User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0\r\n",
+            "Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n",
+            "Accept-Language: en-US,en;q=0.5\r\n",
+            "Accept-Encoding: gzip, deflate, br\r\n",
+            "Connection: keep-alive\r\n",
+            "Upgrade-Insecure-Requests: 1\r\n",
+            "Cache-Control: max-age=0\r\n",
+            "\r\n"
+        );
+
+        let count = 200_000;
+        let source = request_str.repeat(count);
+        let mut reader = reader(source.as_bytes());
+
+        let start = std::time::Instant::now();
+        for _ in 0..count {
+            reader.read().await.unwrap().unwrap();
+        }
+
+        let elapsed = start.elapsed();
+        println!(
+            "parsed {} requests in {:?} ({} ns/request)",
+            count,
+            elapsed,
+            elapsed.as_nanos() / count as u128
+        );
+    }
 }
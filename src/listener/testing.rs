@@ -0,0 +1,55 @@
+//! Scratch files for tests, in directories removed once a test is done with them.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of its own for a test, removed on drop. Its path is canonical, so it can
+/// serve as a root directory.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory, named after `name` and unique to this one.
+    pub fn new(name: &str) -> Self {
+        let number = DIRS.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "http-server-{}-{}-{}",
+            std::process::id(),
+            number,
+            name
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.canonicalize().unwrap())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes a file at `relative`, creating the directories it goes in. Returns its path.
+    pub fn write(&self, relative: &str, content: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// The names of the files directly in the directory, sorted.
+    pub fn entries(&self) -> Vec<String> {
+        let mut entries: Vec<_> = std::fs::read_dir(&self.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        entries
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,626 @@
+//! Changing files under the root directory: storing uploads, in full or in part, and deleting
+//! files.
+
+use crate::{
+    listener::{
+        conditional::{self, Validators},
+        range,
+        request::{HttpRequest, HttpRequestError, RequestBody},
+        HttpResponse,
+    },
+    log,
+    options::FileOptions,
+};
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+/// Numbers the temporary files of uploads, so concurrent ones to the same path don't collide.
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Responds to a POST storing the request body as the file at `path`, with 201. The body is
+/// written to a temporary file next to it, which replaces the file only once all of the body
+/// has arrived, so readers see either the old file or the new one and never part of an
+/// upload. With `fsync`, the new file is also on disk before the response says it was created.
+pub async fn post(request: &mut HttpRequest, path: &Path, options: &FileOptions) -> HttpResponse {
+    match replace(request, path, options).await {
+        Ok(_) => HttpResponse::status(201, "Created"),
+        Err(response) => response,
+    }
+}
+
+/// Responds to a PUT creating the file at `path` with 201, or replacing it with 204. The file
+/// is stored the same way as for a POST, and repeating the request has the same effect as
+/// making it once.
+pub async fn put(request: &mut HttpRequest, path: &Path, options: &FileOptions) -> HttpResponse {
+    match replace(request, path, options).await {
+        Ok(true) => HttpResponse::status(201, "Created"),
+        Ok(false) => HttpResponse::status(204, "No Content"),
+        Err(response) => response,
+    }
+}
+
+/// Responds to a PATCH writing the request body into the existing file at `path`, with 204.
+/// The body goes where the request's `Content-Range` says, which may extend the file but not
+/// leave a gap in it, or at the end of the file without one. The file is copied to a
+/// temporary file that's changed and renamed into place, so readers never see a partial
+/// update.
+///
+/// That makes each PATCH cost as much I/O as the whole file, however little it writes, and
+/// needs room on disk for a second copy while it runs. Appending to a large file a piece at
+/// a time is therefore slow; clients storing large files should upload them whole, or in
+/// fewer, larger pieces.
+pub async fn patch(request: &mut HttpRequest, path: &Path, options: &FileOptions) -> HttpResponse {
+    match update(request, path, options).await {
+        Ok(()) => HttpResponse::status(204, "No Content"),
+        Err(response) => response,
+    }
+}
+
+/// Responds to a DELETE removing the file at `path`, or the directory if it's empty, with
+/// 204. With `recursive`, directories are removed along with their contents, as in WebDAV.
+/// The root directory itself can't be removed.
+pub async fn delete(
+    request: &HttpRequest,
+    root: &Path,
+    path: &Path,
+    recursive: bool,
+) -> HttpResponse {
+    if path == root {
+        return HttpResponse::status(403, "Forbidden");
+    }
+
+    // a symbolic link is removed itself, rather than what it points to
+    let metadata = match fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::status(404, "Not Found")
+        }
+        Err(err) => return internal_error("reading metadata of", path, err),
+    };
+
+    let current = Validators::of(&metadata);
+    if let Some(response) = conditional::evaluate_change(Some(&current), &request.headers) {
+        return response;
+    }
+
+    let removed = if metadata.is_dir() && recursive {
+        fs::remove_dir_all(path).await
+    } else if metadata.is_dir() {
+        fs::remove_dir(path).await
+    } else {
+        fs::remove_file(path).await
+    };
+
+    match removed {
+        Ok(()) => HttpResponse::status(204, "No Content"),
+        Err(err) if err.kind() == io::ErrorKind::DirectoryNotEmpty => {
+            HttpResponse::status(409, "Conflict")
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => HttpResponse::status(404, "Not Found"),
+        Err(err) => internal_error("removing", path, err),
+    }
+}
+
+/// Stores the request body as the file at `path`, if the request's preconditions hold.
+/// Returns whether the file was created rather than replaced.
+async fn replace(
+    request: &mut HttpRequest,
+    path: &Path,
+    options: &FileOptions,
+) -> Result<bool, HttpResponse> {
+    let current = current(path).await?;
+    let validators = current.as_ref().map(Validators::of);
+    if let Some(response) = conditional::evaluate_change(validators.as_ref(), &request.headers) {
+        return Err(response);
+    }
+
+    parent_dir(path, options.create_dirs).await?;
+    store(&mut request.body, path, None, options.fsync)
+        .await
+        .map_err(|err| err.to_response(path))?;
+
+    Ok(current.is_none())
+}
+
+/// Writes the request body into the existing file at `path`, if the request's preconditions
+/// hold.
+async fn update(
+    request: &mut HttpRequest,
+    path: &Path,
+    options: &FileOptions,
+) -> Result<(), HttpResponse> {
+    let current = current(path)
+        .await?
+        .ok_or_else(|| HttpResponse::status(404, "Not Found"))?;
+    let validators = Validators::of(&current);
+    if let Some(response) = conditional::evaluate_change(Some(&validators), &request.headers) {
+        return Err(response);
+    }
+
+    let patch = match request.headers.get("content-range") {
+        Some(value) => {
+            let range = std::str::from_utf8(value)
+                .ok()
+                .and_then(range::parse_content_range)
+                .ok_or_else(|| {
+                    HttpResponse::error(400, "Bad Request", "malformed content-range".to_string())
+                })?;
+            Patch {
+                offset: range.start,
+                len: Some(range.end - range.start),
+            }
+        }
+        None => Patch {
+            offset: current.len(),
+            len: None,
+        },
+    };
+
+    if patch.offset > current.len() {
+        return Err(HttpResponse::error(
+            416,
+            "Range Not Satisfiable",
+            "range starts past the end of the file".to_string(),
+        )
+        .with_header("content-range", &format!("bytes */{}", current.len())));
+    }
+
+    store(&mut request.body, path, Some(patch), options.fsync)
+        .await
+        .map_err(|err| err.to_response(path))
+}
+
+/// The metadata of the file at `path`, or `None` if there's no file there. A directory is a
+/// conflict, as it can't be written like a file.
+async fn current(path: &Path) -> Result<Option<Metadata>, HttpResponse> {
+    match fs::metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => Err(HttpResponse::status(409, "Conflict")),
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(internal_error("reading metadata of", path, err)),
+    }
+}
+
+/// Makes sure the directory the file at `path` goes in exists, creating it and any missing
+/// above it with `create_dirs`. Otherwise a missing directory is a conflict, as in WebDAV (RFC
+/// 4918 section 9.7.1).
+async fn parent_dir(path: &Path, create_dirs: bool) -> Result<(), HttpResponse> {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    let result = if create_dirs {
+        fs::create_dir_all(parent).await
+    } else {
+        match fs::metadata(parent).await {
+            Ok(metadata) if !metadata.is_dir() => Err(io::ErrorKind::NotADirectory.into()),
+            result => result.map(|_| ()),
+        }
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::NotADirectory
+                    | io::ErrorKind::AlreadyExists
+            ) =>
+        {
+            Err(HttpResponse::status(409, "Conflict"))
+        }
+        Err(err) => Err(internal_error("creating directories for", path, err)),
+    }
+}
+
+/// Logs a failure to `action` the file at `path`, and responds with 500.
+pub(super) fn internal_error(action: &str, path: &Path, err: io::Error) -> HttpResponse {
+    log::error!("Error {} {:?}: {}", action, path, err);
+    HttpResponse::status(500, "Internal Server Error")
+}
+
+/// Where a PATCH writes its body: from `offset` on, and exactly `len` bytes if that's known.
+struct Patch {
+    offset: u64,
+    len: Option<u64>,
+}
+
+/// Why an upload wasn't stored.
+#[derive(Debug)]
+enum UploadError {
+    /// The body couldn't be read in full, because it was shorter than announced or malformed.
+    Body(HttpRequestError),
+    /// The body wasn't as long as the `Content-Range` of a PATCH said.
+    LengthMismatch,
+    Io(io::Error),
+}
+
+impl UploadError {
+    fn to_response(&self, path: &Path) -> HttpResponse {
+        match self {
+            Self::Body(err) => {
+                log::debug!("Discarding upload to {:?}: {}", path, err);
+                err.to_response()
+            }
+            Self::LengthMismatch => HttpResponse::error(
+                400,
+                "Bad Request",
+                "body length doesn't match content-range".to_string(),
+            ),
+            Self::Io(err) => {
+                log::error!("Error storing upload to file {:?}: {}", path, err);
+                HttpResponse::status(500, "Internal Server Error")
+            }
+        }
+    }
+}
+
+impl From<HttpRequestError> for UploadError {
+    fn from(value: HttpRequestError) -> Self {
+        Self::Body(value)
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Writes `body` to a temporary file, then renames that into place at `path`. A file replaced
+/// this way keeps its permissions. For a `patch`, the temporary file starts out as a copy of
+/// the file at `path`.
+async fn store(
+    body: &mut RequestBody,
+    path: &Path,
+    patch: Option<Patch>,
+    fsync: bool,
+) -> Result<(), UploadError> {
+    let (mut file, temp) = TempFile::create(path).await?;
+
+    match fs::metadata(path).await {
+        Ok(replaced) => file.set_permissions(replaced.permissions()).await?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    if let Some(patch) = &patch {
+        let mut original = File::open(path).await?;
+        tokio::io::copy(&mut original, &mut file).await?;
+        file.seek(SeekFrom::Start(patch.offset)).await?;
+    }
+
+    // written as it arrives, so the body is never held in memory as a whole
+    let mut written = 0;
+    while let Some(piece) = body.chunk().await? {
+        file.write_all(&piece).await?;
+        written += piece.len() as u64;
+    }
+
+    if let Some(Patch { len: Some(len), .. }) = patch {
+        if written != len {
+            return Err(UploadError::LengthMismatch);
+        }
+    }
+
+    file.flush().await?;
+    if fsync {
+        file.sync_all().await?;
+    }
+    drop(file);
+
+    temp.persist(path).await?;
+    if fsync {
+        // the rename is only durable once the directory entry is
+        if let Some(parent) = path.parent() {
+            File::open(parent).await?.sync_all().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A file holding an upload in progress, or anything else being put together next to its
+/// destination so it can be renamed into place. It's hidden, and removed on drop unless it
+/// was renamed, including when the upload is cancelled.
+pub(super) struct TempFile(Option<PathBuf>);
+
+impl TempFile {
+    /// Picks a name next to `destination` that no other temporary file uses, without creating
+    /// anything there yet.
+    pub(super) fn next_to(destination: &Path) -> Self {
+        let name = destination
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let number = UPLOADS.fetch_add(1, Ordering::Relaxed);
+        Self(Some(destination.with_file_name(format!(
+            ".{}.{}-{}.upload",
+            name,
+            std::process::id(),
+            number
+        ))))
+    }
+
+    async fn create(destination: &Path) -> io::Result<(File, Self)> {
+        let temp = Self::next_to(destination);
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(temp.path())
+            .await?;
+
+        Ok((file, temp))
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        self.0.as_ref().expect("temporary file not persisted yet")
+    }
+
+    pub(super) async fn persist(mut self, destination: &Path) -> io::Result<()> {
+        fs::rename(self.path(), destination).await?;
+        self.0 = None;
+        Ok(())
+    }
+
+    /// Removes the file now, or the directory with everything in it.
+    pub(super) async fn remove(mut self) -> io::Result<()> {
+        let Some(path) = self.0.take() else {
+            return Ok(());
+        };
+        match fs::symlink_metadata(&path).await?.is_dir() {
+            true => fs::remove_dir_all(path).await,
+            false => fs::remove_file(path).await,
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {
+                let _ = std::fs::remove_dir_all(path);
+            }
+            Ok(_) => {
+                let _ = std::fs::remove_file(path);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        listener::{request::RequestReader, testing::TempDir},
+        options::ServerOptions,
+    };
+
+    /// A request with `head` and `body`, whose body is passed on the way the connection does,
+    /// ending with any error reading it.
+    async fn request(head: &str, body: &str) -> HttpRequest {
+        let input = format!("{}\r\n\r\n{}", head, body);
+        let server = ServerOptions::default();
+        let mut reader = RequestReader::new(input.as_bytes(), server.limits, server.timeouts);
+        let mut request = reader.read_head().await.unwrap().unwrap();
+
+        let (body_tx, body_rx) = tokio::sync::mpsc::channel(4);
+        loop {
+            match reader.read_body().await {
+                Ok(Some(piece)) => body_tx.send(Ok(piece)).await.unwrap(),
+                Ok(None) => break,
+                Err(err) => {
+                    body_tx.send(Err(err)).await.unwrap();
+                    break;
+                }
+            }
+        }
+        request.body = RequestBody::Stream(body_rx);
+        request
+    }
+
+    async fn upload(path: &Path, body: &str, options: &FileOptions) -> HttpResponse {
+        let head = "POST /files/upload HTTP/1.1\r\ncontent-length: 5";
+        post(&mut request(head, body).await, path, options).await
+    }
+
+    #[tokio::test]
+    async fn replace_files_atomically() {
+        let dir = TempDir::new("replace");
+        let path = dir.path().join("upload");
+        std::fs::write(&path, "old").unwrap();
+
+        for fsync in [false, true] {
+            let options = FileOptions {
+                fsync,
+                ..Default::default()
+            };
+            let response = upload(&path, "hello", &options).await;
+            assert_eq!(response.status_code, 201);
+            assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+            assert_eq!(dir.entries(), ["upload"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_permissions_of_replaced_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("permissions");
+        let path = dir.write("file", "old");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        for (mode_before, replace) in [(0o644, "post"), (0o640, "put"), (0o604, "patch")] {
+            let permissions = std::fs::Permissions::from_mode(mode_before);
+            std::fs::set_permissions(&path, permissions).unwrap();
+            let status = match replace {
+                "post" => {
+                    upload(&path, "hello", &FileOptions::default())
+                        .await
+                        .status_code
+                }
+                "put" => put_with(&path, "").await,
+                _ => patch_with(&path, "", "!").await.status_code,
+            };
+            assert!(status < 300, "{}: {}", replace, status);
+            assert_eq!(mode(&path), mode_before, "{}", replace);
+        }
+    }
+
+    #[tokio::test]
+    async fn discard_short_bodies() {
+        let dir = TempDir::new("short");
+        let path = dir.path().join("upload");
+        std::fs::write(&path, "old").unwrap();
+
+        let response = upload(&path, "hel", &FileOptions::default()).await;
+        assert_eq!(response.status_code, 400);
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        assert_eq!(dir.entries(), ["upload"]);
+    }
+
+    #[tokio::test]
+    async fn refuse_to_replace_directories() {
+        let dir = TempDir::new("directory");
+        let response = upload(dir.path(), "hello", &FileOptions::default()).await;
+        assert_eq!(response.status_code, 409);
+        assert!(dir.entries().is_empty());
+    }
+
+    async fn put_with(path: &Path, fields: &str) -> u16 {
+        let head = format!("PUT /files/file HTTP/1.1\r\ncontent-length: 5{}", fields);
+        let mut request = request(&head, "hello").await;
+        put(&mut request, path, &FileOptions::default())
+            .await
+            .status_code
+    }
+
+    async fn patch_with(path: &Path, fields: &str, body: &str) -> HttpResponse {
+        let head = format!(
+            "PATCH /files/file HTTP/1.1\r\ncontent-length: {}{}",
+            body.len(),
+            fields
+        );
+        let mut request = request(&head, body).await;
+        patch(&mut request, path, &FileOptions::default()).await
+    }
+
+    async fn delete_with(root: &Path, path: &str, fields: &str) -> u16 {
+        let head = format!("DELETE /files/{} HTTP/1.1{}", path, fields);
+        let request = request(&head, "").await;
+        delete(&request, root, &root.join(path), false)
+            .await
+            .status_code
+    }
+
+    #[tokio::test]
+    async fn create_or_replace_with_put() {
+        let dir = TempDir::new("put");
+        let path = dir.path().join("file");
+
+        // only creates the file if there isn't one
+        assert_eq!(put_with(&path, "\r\nif-match: *").await, 412);
+        assert_eq!(put_with(&path, "\r\nif-none-match: *").await, 201);
+        assert_eq!(put_with(&path, "\r\nif-none-match: *").await, 412);
+        assert_eq!(put_with(&path, "").await, 204);
+        assert_eq!(put_with(&path, "\r\nif-match: *").await, 204);
+        assert_eq!(put_with(&path, "\r\nif-match: \"x\"").await, 412);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn create_missing_directories() {
+        let dir = TempDir::new("create-dirs");
+        let path = dir.path().join("a/b/file");
+
+        let response = upload(&path, "hello", &FileOptions::default()).await;
+        assert_eq!(response.status_code, 409);
+        assert!(dir.entries().is_empty());
+
+        let options = FileOptions {
+            create_dirs: true,
+            ..Default::default()
+        };
+        let response = upload(&path, "hello", &options).await;
+        assert_eq!(response.status_code, 201);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+        // a file can't stand in for a directory
+        let response = upload(&path.join("file"), "hello", &options).await;
+        assert_eq!(response.status_code, 409);
+    }
+
+    #[tokio::test]
+    async fn write_parts_of_files_with_patch() {
+        let dir = TempDir::new("patch");
+        let path = dir.path().join("file");
+
+        assert_eq!(patch_with(&path, "", "hello").await.status_code, 404);
+        std::fs::write(&path, "hello").unwrap();
+
+        // appends without a range
+        assert_eq!(patch_with(&path, "", ", world").await.status_code, 204);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, world");
+
+        let response = patch_with(&path, "\r\ncontent-range: bytes 7-11/*", "there").await;
+        assert_eq!(response.status_code, 204);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, there");
+
+        let response = patch_with(&path, "\r\ncontent-range: bytes 12-13/14", "!!").await;
+        assert_eq!(response.status_code, 204);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, there!!");
+
+        // leaves the file alone unless the request makes sense
+        let response = patch_with(&path, "\r\ncontent-range: bytes 20-21/*", "!!").await;
+        assert_eq!(response.status_code, 416);
+        assert_eq!(response.header("content-range"), Some("bytes */14"));
+        let response = patch_with(&path, "\r\ncontent-range: bytes 0-9/*", "short").await;
+        assert_eq!(response.status_code, 400);
+        let response = patch_with(&path, "\r\ncontent-range: 0-4", "hello").await;
+        assert_eq!(response.status_code, 400);
+        let response = patch_with(&path, "\r\nif-match: \"x\"", "hello").await;
+        assert_eq!(response.status_code, 412);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, there!!");
+        assert_eq!(dir.entries(), ["file"]);
+    }
+
+    #[tokio::test]
+    async fn delete_files_and_empty_directories() {
+        let dir = TempDir::new("delete");
+        std::fs::create_dir_all(dir.path().join("full")).unwrap();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        std::fs::write(dir.path().join("full/file"), "hello").unwrap();
+
+        assert_eq!(delete_with(dir.path(), "", "").await, 403);
+        assert_eq!(delete_with(dir.path(), "full", "").await, 409);
+        let status = delete_with(dir.path(), "full/file", "\r\nif-match: \"x\"").await;
+        assert_eq!(status, 412);
+        assert_eq!(delete_with(dir.path(), "full/file", "").await, 204);
+        assert_eq!(delete_with(dir.path(), "full/file", "").await, 404);
+        assert_eq!(delete_with(dir.path(), "full", "").await, 204);
+        assert_eq!(delete_with(dir.path(), "empty", "").await, 204);
+        assert!(dir.entries().is_empty());
+    }
+}
@@ -0,0 +1,1633 @@
+//! WebDAV (RFC 4918, classes 1 and 2) for files under the root directory, so clients can
+//! mount it: properties, directories as collections, copying and moving, and write locks.
+
+use crate::{
+    listener::{
+        body::Body,
+        conditional::{precondition_failed, Validators},
+        handler,
+        listing::{self, escape, percent_encode},
+        locks::{self, Lock, Locks},
+        mime, path,
+        request::HttpRequest,
+        upload::{self, internal_error, TempFile},
+        xml::{Element, DAV},
+        HttpResponse,
+    },
+    log,
+    options::{FileOptions, SymlinkPolicy},
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::fs::{self, OpenOptions};
+
+/// The methods WebDAV adds, as listed in `Allow` fields.
+pub const METHODS: &str = "OPTIONS, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+
+/// Most dead properties a file may have. Setting more is refused with 507, so clients can't
+/// fill the server's memory with them.
+const MAX_PROPERTIES: usize = 100;
+
+/// Files whose properties are kept before any are checked for having been deleted.
+const MIN_SWEPT: usize = 64;
+
+/// What's shared by all connections: the locks held, and the properties clients set on
+/// files. Both are kept in memory only, and lost when the server stops.
+#[derive(Debug, Default)]
+pub struct State {
+    locks: Locks,
+    /// Dead properties (RFC 4918 section 4.2), by the path of the file they're set on.
+    properties: Mutex<HashMap<PathBuf, Vec<Element>>>,
+    /// How many files had properties after those of deleted files were last dropped.
+    swept: AtomicUsize,
+}
+
+impl State {
+    fn properties(&self, path: &Path) -> Vec<Element> {
+        let properties = self.properties.lock().unwrap();
+        properties.get(path).cloned().unwrap_or_default()
+    }
+
+    /// Drops the locks and properties of `path` and anything inside it, after those were
+    /// deleted or replaced.
+    fn forget(&self, path: &Path) {
+        self.locks.remove_within(path);
+        let mut properties = self.properties.lock().unwrap();
+        properties.retain(|key, _| !key.starts_with(path));
+    }
+
+    /// Drops the properties of files that were deleted other than through WebDAV. That's
+    /// done once twice as many files have properties as after the last time, so it takes
+    /// constant time per file on average.
+    async fn drop_deleted(&self) {
+        let paths: Vec<_> = {
+            let properties = self.properties.lock().unwrap();
+            let swept = self.swept.load(Ordering::Relaxed).max(MIN_SWEPT / 2);
+            if properties.len() < swept * 2 {
+                return;
+            }
+            properties.keys().cloned().collect()
+        };
+
+        let mut deleted = Vec::new();
+        for path in paths {
+            if let Err(err) = fs::symlink_metadata(&path).await {
+                if err.kind() == io::ErrorKind::NotFound {
+                    deleted.push(path);
+                }
+            }
+        }
+
+        let mut properties = self.properties.lock().unwrap();
+        for path in deleted {
+            properties.remove(&path);
+        }
+        self.swept.store(properties.len(), Ordering::Relaxed);
+    }
+
+    /// Gives the files copied or moved from `from` to `to` the properties of the originals,
+    /// including those inside a directory if `infinite`.
+    fn carry_properties(&self, from: &Path, to: &Path, moved: bool, infinite: bool) {
+        let mut properties = self.properties.lock().unwrap();
+        let carried: Vec<_> = properties
+            .iter()
+            .filter(|(key, _)| *key == from || (infinite && key.starts_with(from)))
+            .map(|(key, values)| (key.clone(), values.clone()))
+            .collect();
+
+        for (key, values) in carried {
+            if moved {
+                properties.remove(&key);
+            }
+            let relative = key
+                .strip_prefix(from)
+                .expect("carried keys start with the source");
+            properties.insert(to.join(relative), values);
+        }
+    }
+}
+
+/// Handles the WebDAV methods for the file at `path`, after checking the request's `If`
+/// field and that it holds the locks it needs, whatever its method. Returns `None` for
+/// requests the plain file handlers take care of.
+pub async fn handle(
+    state: &State,
+    request: &mut HttpRequest,
+    root: &Path,
+    path: &Path,
+    options: &FileOptions,
+) -> Option<HttpResponse> {
+    let submitted = match check(state, request, root, path, options.symlinks).await {
+        Ok(submitted) => submitted,
+        Err(response) => return Some(response),
+    };
+
+    let response = match request.method() {
+        "OPTIONS" => HttpResponse::status(200, "OK")
+            .with_header("allow", &format!("{}, {}", handler::FILE_METHODS, METHODS))
+            .with_header("dav", "1, 2")
+            .with_header("ms-author-via", "DAV"),
+        "PROPFIND" => propfind(state, request, root, path, options).await,
+        "PROPPATCH" => proppatch(state, request, root, path).await,
+        "MKCOL" => mkcol(request, path).await,
+        "COPY" => transfer(state, request, root, path, options, &submitted, false).await,
+        "MOVE" => transfer(state, request, root, path, options, &submitted, true).await,
+        "LOCK" => lock(state, request, root, path, &submitted).await,
+        "UNLOCK" => unlock(state, request, path),
+        "DELETE" => {
+            let response = upload::delete(request, root, path, true).await;
+            if response.status_code == 204 {
+                state.forget(path);
+            }
+            response
+        }
+        _ => return None,
+    };
+
+    Some(response)
+}
+
+/// Evaluates the request's `If` field (RFC 4918 section 10.4), failing with 412 if it
+/// doesn't hold, and makes sure a request that changes the file at `path` submits the tokens
+/// of the locks protecting it, failing with 423 otherwise. Returns the submitted tokens.
+async fn check(
+    state: &State,
+    request: &HttpRequest,
+    root: &Path,
+    path: &Path,
+    symlinks: SymlinkPolicy,
+) -> Result<Vec<String>, HttpResponse> {
+    let lists = match request.headers.get("if") {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(parse_if)
+            .ok_or_else(|| bad_request("malformed if field"))?,
+        None => Vec::new(),
+    };
+
+    if !lists.is_empty() && !evaluate_if(state, &lists, root, path, symlinks).await {
+        return Err(precondition_failed());
+    }
+
+    let submitted: Vec<_> = lists
+        .into_iter()
+        .flat_map(|list| list.conditions)
+        .filter_map(|condition| match condition.test {
+            Test::Token(token) => Some(token),
+            Test::ETag(_) => None,
+        })
+        .collect();
+
+    // methods that add or remove directory members also change the directory
+    let protecting = match request.method() {
+        "PATCH" | "PROPPATCH" => protecting(&state.locks, path, false),
+        "PUT" | "POST" | "MKCOL" | "DELETE" | "MOVE" => protecting(&state.locks, path, true),
+        _ => Vec::new(),
+    };
+    match require_tokens(&protecting, &submitted) {
+        Some(response) => Err(response),
+        None => Ok(submitted),
+    }
+}
+
+/// The locks a request changing the file at `path` needs tokens for: those covering it and
+/// anything inside it, and with `membership`, a lock on its directory alone too.
+fn protecting(locks: &Locks, path: &Path, membership: bool) -> Vec<Lock> {
+    let mut protecting = locks.covering(path);
+    protecting.extend(
+        locks
+            .within(path)
+            .into_iter()
+            .filter(|lock| lock.root != path),
+    );
+
+    if membership {
+        if let Some(parent) = path.parent() {
+            let parent_locks = locks.covering(parent).into_iter();
+            protecting.extend(parent_locks.filter(|lock| !lock.infinite));
+        }
+    }
+
+    protecting
+}
+
+/// Refuses with 423 unless, for each of `locks`, the token of a lock on the same file was
+/// submitted. Any one of several shared locks will do.
+fn require_tokens(locks: &[Lock], submitted: &[String]) -> Option<HttpResponse> {
+    let held = |lock: &Lock| {
+        locks
+            .iter()
+            .any(|other| other.root == lock.root && submitted.contains(&other.token))
+    };
+
+    match locks.iter().all(held) {
+        true => None,
+        false => Some(locked()),
+    }
+}
+
+/// A list of conditions in an `If` field. It holds when all of its conditions do, for the
+/// file its tag names, or the request's target if it has none.
+#[derive(Debug, PartialEq)]
+struct IfList {
+    tag: Option<String>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Condition {
+    not: bool,
+    test: Test,
+}
+
+/// What a condition compares with the file: a lock token on it, or its entity tag.
+#[derive(Debug, PartialEq)]
+enum Test {
+    Token(String),
+    ETag(String),
+}
+
+/// Parses an `If` field. Returns `None` if it's malformed.
+fn parse_if(value: &str) -> Option<Vec<IfList>> {
+    let mut lists = Vec::new();
+    let mut tag = None;
+    let mut rest = value.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(tagged) = rest.strip_prefix('<') {
+            let end = tagged.find('>')?;
+            tag = Some(tagged[..end].to_string());
+            rest = tagged[end + 1..].trim_start();
+            if !rest.starts_with('(') {
+                return None;
+            }
+            continue;
+        }
+
+        let mut list = rest.strip_prefix('(')?.trim_start();
+        let mut conditions = Vec::new();
+        while !list.is_empty() && !list.starts_with(')') {
+            let not = list
+                .get(..3)
+                .is_some_and(|word| word.eq_ignore_ascii_case("not"));
+            let condition = if not { list[3..].trim_start() } else { list };
+
+            let (test, after) = if let Some(token) = condition.strip_prefix('<') {
+                let end = token.find('>')?;
+                (Test::Token(token[..end].to_string()), &token[end + 1..])
+            } else if let Some(etag) = condition.strip_prefix('[') {
+                let end = etag.find(']')?;
+                (Test::ETag(etag[..end].to_string()), &etag[end + 1..])
+            } else {
+                return None;
+            };
+
+            conditions.push(Condition { not, test });
+            list = after.trim_start();
+        }
+
+        if conditions.is_empty() {
+            return None;
+        }
+        lists.push(IfList {
+            tag: tag.clone(),
+            conditions,
+        });
+        rest = list.strip_prefix(')')?.trim_start();
+    }
+
+    (!lists.is_empty()).then_some(lists)
+}
+
+/// Whether any of the lists in an `If` field holds. A token matches if it's that of a lock on
+/// the file, and an entity tag if it matches the file's in the weak comparison.
+async fn evaluate_if(
+    state: &State,
+    lists: &[IfList],
+    root: &Path,
+    path: &Path,
+    symlinks: SymlinkPolicy,
+) -> bool {
+    for list in lists {
+        let resource = match &list.tag {
+            Some(tag) => match resolve_uri(root, tag, symlinks).await {
+                Ok(resource) => resource,
+                Err(_) => continue,
+            },
+            None => path.to_path_buf(),
+        };
+
+        let locks = state.locks.covering(&resource);
+        let validators = fs::metadata(&resource)
+            .await
+            .ok()
+            .map(|metadata| Validators::of(&metadata));
+        let holds = list.conditions.iter().all(|condition| {
+            let matches = match &condition.test {
+                Test::Token(token) => locks.iter().any(|lock| &lock.token == token),
+                Test::ETag(etag) => validators
+                    .as_ref()
+                    .is_some_and(|validators| validators.matches_etag(etag)),
+            };
+            matches != condition.not
+        });
+
+        if holds {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Resolves a URI naming a file on this server, as `Destination` fields and tags in `If`
+/// fields do, either in full or as an absolute path. Fails with 502 if it's outside
+/// `/files/`, as another server would have to handle it (RFC 4918 section 9.8.5).
+async fn resolve_uri(
+    root: &Path,
+    uri: &str,
+    symlinks: SymlinkPolicy,
+) -> Result<PathBuf, HttpResponse> {
+    let uri = uri.trim();
+    let absolute_path = match uri.find("://") {
+        Some(scheme_end) => {
+            let authority_and_path = &uri[scheme_end + 3..];
+            authority_and_path
+                .find('/')
+                .map_or("/", |start| &authority_and_path[start..])
+        }
+        None => uri,
+    };
+
+    match absolute_path.strip_prefix("/files/") {
+        Some(target) => path::resolve(root, target, symlinks)
+            .await
+            .map_err(|err| err.to_response()),
+        None => Err(HttpResponse::status(502, "Bad Gateway")),
+    }
+}
+
+/// The `Depth` field of a request (RFC 4918 section 10.2).
+#[derive(Debug, PartialEq)]
+enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+/// The request's depth, infinity if it doesn't say. Returns `None` if it's not valid.
+fn depth(request: &HttpRequest) -> Option<Depth> {
+    match request.headers.get("depth") {
+        None => Some(Depth::Infinity),
+        Some(b"0") => Some(Depth::Zero),
+        Some(b"1") => Some(Depth::One),
+        Some(value) if value.eq_ignore_ascii_case(b"infinity") => Some(Depth::Infinity),
+        Some(_) => None,
+    }
+}
+
+/// Reads the whole request body, which for WebDAV methods is a small XML document if any.
+async fn read_body(request: &mut HttpRequest) -> Result<Vec<u8>, HttpResponse> {
+    match std::mem::take(&mut request.body).read_to_end().await {
+        Ok(body) => Ok(body.to_vec()),
+        Err(err) => Err(err.to_response()),
+    }
+}
+
+/// Parses a request body whose root element has to be `name` in the DAV namespace. Returns
+/// `None` if it's malformed or something else.
+fn parse_body(body: &[u8], name: &str) -> Option<Element> {
+    Element::parse(body).filter(|root| root.is(DAV, name))
+}
+
+/// Which properties a PROPFIND asks for.
+enum Wanted {
+    All,
+    Names,
+    Some(Vec<Element>),
+}
+
+/// Responds to a PROPFIND with the properties of the file at `path`, and with depth 1 those
+/// of the files in it too. Infinite depth isn't supported, since it could mean walking the
+/// whole root directory (RFC 4918 section 9.1).
+async fn propfind(
+    state: &State,
+    request: &mut HttpRequest,
+    root: &Path,
+    path: &Path,
+    options: &FileOptions,
+) -> HttpResponse {
+    let members = match depth(request) {
+        Some(Depth::Zero) => false,
+        Some(Depth::One) => true,
+        Some(Depth::Infinity) => {
+            return xml_response(
+                403,
+                "Forbidden",
+                "<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+            )
+        }
+        None => return bad_request("invalid depth"),
+    };
+
+    let body = match read_body(request).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let wanted = if body.iter().all(u8::is_ascii_whitespace) {
+        Wanted::All
+    } else {
+        let propfind = match parse_body(&body, "propfind") {
+            Some(propfind) => propfind,
+            None => return bad_request("expected a propfind document"),
+        };
+        if propfind.child(DAV, "propname").is_some() {
+            Wanted::Names
+        } else if let Some(prop) = propfind.child(DAV, "prop") {
+            Wanted::Some(prop.children.iter().map(Element::name_only).collect())
+        } else if propfind.child(DAV, "allprop").is_some() {
+            Wanted::All
+        } else {
+            return bad_request("expected allprop, propname or prop");
+        }
+    };
+
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::status(404, "Not Found")
+        }
+        Err(err) => return internal_error("reading metadata of", path, err),
+    };
+
+    let mut resources = vec![(path.to_path_buf(), metadata)];
+    if members && resources[0].1.is_dir() {
+        match children(path, options).await {
+            Ok(children) => resources.extend(children),
+            Err(err) => return internal_error("listing", path, err),
+        }
+    }
+
+    let mut multistatus = Multistatus::new();
+    for (path, metadata) in resources {
+        let live = live_properties(state, root, &path, &metadata, options);
+        let dead = state.properties(&path);
+        let (mut found, mut missing) = (String::new(), String::new());
+
+        match &wanted {
+            Wanted::All => {
+                for (name, value) in &live {
+                    write_property(&mut found, &Element::new(DAV, name), value);
+                }
+                for property in &dead {
+                    property.write(&mut found);
+                }
+            }
+            Wanted::Names => {
+                for (name, _) in &live {
+                    write_property(&mut found, &Element::new(DAV, name), "");
+                }
+                for property in &dead {
+                    write_property(&mut found, property, "");
+                }
+            }
+            Wanted::Some(names) => {
+                for name in names {
+                    let live = live
+                        .iter()
+                        .find(|(live, _)| name.is(DAV, live))
+                        .map(|(_, value)| value.clone());
+                    let dead = dead.iter().find(|dead| dead.name_only() == *name);
+                    match (live, dead) {
+                        (Some(value), _) => write_property(&mut found, name, &value),
+                        (None, Some(dead)) => dead.write(&mut found),
+                        (None, None) => write_property(&mut missing, name, ""),
+                    }
+                }
+            }
+        }
+
+        let href = href(root, &path, metadata.is_dir());
+        multistatus.response(&href, &[(200, found), (404, missing)]);
+    }
+
+    multistatus.into_response()
+}
+
+/// The live properties of a file, those the server maintains, by name in the DAV namespace
+/// and with their values written out.
+fn live_properties(
+    state: &State,
+    root: &Path,
+    path: &Path,
+    metadata: &Metadata,
+    options: &FileOptions,
+) -> Vec<(&'static str, String)> {
+    let mut properties = Vec::new();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    properties.push(("displayname", escape(&name)));
+
+    let validators = Validators::of(metadata);
+    if metadata.is_dir() {
+        properties.push(("resourcetype", "<D:collection/>".to_string()));
+    } else {
+        let content_type = mime::from_extension(path, &options.mime_types)
+            .unwrap_or_else(|| mime::DEFAULT_TYPE.to_string());
+        properties.push(("resourcetype", String::new()));
+        properties.push(("getcontentlength", metadata.len().to_string()));
+        properties.push(("getcontenttype", escape(&content_type)));
+        properties.push(("getetag", escape(&validators.etag())));
+    }
+    if let Some(last_modified) = validators.last_modified() {
+        properties.push(("getlastmodified", last_modified));
+    }
+
+    let mut supported = String::new();
+    for scope in ["exclusive", "shared"] {
+        write!(
+            supported,
+            "<D:lockentry><D:lockscope><D:{}/></D:lockscope>\
+             <D:locktype><D:write/></D:locktype></D:lockentry>",
+            scope
+        )
+        .unwrap();
+    }
+    properties.push(("supportedlock", supported));
+
+    let mut discovery = String::new();
+    for lock in state.locks.covering(path) {
+        let collection = lock.root != path || metadata.is_dir();
+        write_active_lock(&mut discovery, root, &lock, collection);
+    }
+    properties.push(("lockdiscovery", discovery));
+
+    properties
+}
+
+/// The files in the directory at `path` that a listing would show, with their metadata.
+async fn children(path: &Path, options: &FileOptions) -> io::Result<Vec<(PathBuf, Metadata)>> {
+    let mut children: Vec<_> = listing::read_visible(path, options)
+        .await?
+        .into_iter()
+        .map(|(name, metadata)| (path.join(name), metadata))
+        .collect();
+
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(children)
+}
+
+/// Responds to a PROPPATCH setting and removing the properties of the file at `path`, in the
+/// order the request gives. Either all of the changes are made or none are, and the live
+/// properties in the DAV namespace can't be changed.
+async fn proppatch(
+    state: &State,
+    request: &mut HttpRequest,
+    root: &Path,
+    path: &Path,
+) -> HttpResponse {
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::status(404, "Not Found")
+        }
+        Err(err) => return internal_error("reading metadata of", path, err),
+    };
+
+    let update = match read_body(request).await {
+        Ok(body) => parse_body(&body, "propertyupdate"),
+        Err(response) => return response,
+    };
+    let update = match update {
+        Some(update) => update,
+        None => return bad_request("expected a propertyupdate document"),
+    };
+
+    let mut changes = Vec::new();
+    for instruction in &update.children {
+        let set = match (instruction.is(DAV, "set"), instruction.is(DAV, "remove")) {
+            (true, _) => true,
+            (_, true) => false,
+            _ => continue,
+        };
+        for prop in instruction.children.iter().filter(|c| c.is(DAV, "prop")) {
+            changes.extend(prop.children.iter().map(|property| (set, property)));
+        }
+    }
+    if changes.is_empty() {
+        return bad_request("no properties to set or remove");
+    }
+
+    let (mut succeeded, mut protected) = (String::new(), String::new());
+    let (mut insufficient, mut failed) = (String::new(), String::new());
+    if changes
+        .iter()
+        .any(|(_, property)| property.namespace == DAV)
+    {
+        for (_, property) in &changes {
+            match property.namespace == DAV {
+                true => write_property(&mut protected, property, ""),
+                false => write_property(&mut failed, property, ""),
+            }
+        }
+    } else {
+        state.drop_deleted().await;
+        let mut properties = state.properties.lock().unwrap();
+        let current = properties.get(path).map_or(&[][..], Vec::as_slice);
+        let mut stored = current.to_vec();
+        for &(set, property) in &changes {
+            stored.retain(|stored| stored.name_only() != property.name_only());
+            if set {
+                stored.push(property.clone());
+            }
+        }
+
+        if stored.len() > MAX_PROPERTIES {
+            // nothing is changed, and the properties that didn't fit are the reason
+            for &(set, property) in &changes {
+                let new = !current
+                    .iter()
+                    .any(|stored| stored.name_only() == property.name_only());
+                match set && new {
+                    true => write_property(&mut insufficient, property, ""),
+                    false => write_property(&mut failed, property, ""),
+                }
+            }
+        } else {
+            for &(_, property) in &changes {
+                write_property(&mut succeeded, property, "");
+            }
+            match stored.is_empty() {
+                true => properties.remove(path),
+                false => properties.insert(path.to_path_buf(), stored),
+            };
+        }
+    }
+
+    let mut multistatus = Multistatus::new();
+    multistatus.response(
+        &href(root, path, metadata.is_dir()),
+        &[
+            (200, succeeded),
+            (403, protected),
+            (507, insufficient),
+            (424, failed),
+        ],
+    );
+    multistatus.into_response()
+}
+
+/// Responds to a MKCOL creating a directory at `path`. Its parent has to exist already.
+async fn mkcol(request: &mut HttpRequest, path: &Path) -> HttpResponse {
+    match read_body(request).await {
+        Ok(body) if !body.is_empty() => return HttpResponse::status(415, "Unsupported Media Type"),
+        Ok(_) => {}
+        Err(response) => return response,
+    }
+
+    match fs::create_dir(path).await {
+        Ok(()) => HttpResponse::status(201, "Created"),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            HttpResponse::status(405, "Method Not Allowed")
+        }
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            HttpResponse::status(409, "Conflict")
+        }
+        Err(err) => internal_error("creating directory", path, err),
+    }
+}
+
+/// Responds to a COPY or MOVE of the file at `path` to the request's `Destination`, with 201
+/// if nothing was there yet and 204 if something was replaced. Unless `Overwrite` is `F`,
+/// whatever is at the destination is replaced once the copy or move succeeds. Directories are copied with their
+/// contents unless the depth is 0, and are always moved with them.
+async fn transfer(
+    state: &State,
+    request: &HttpRequest,
+    root: &Path,
+    path: &Path,
+    options: &FileOptions,
+    submitted: &[String],
+    moving: bool,
+) -> HttpResponse {
+    let destination = match request.headers.get("destination").map(std::str::from_utf8) {
+        Some(Ok(destination)) => destination,
+        _ => return bad_request("missing destination"),
+    };
+    let destination = match resolve_uri(root, destination, options.symlinks).await {
+        Ok(destination) => destination,
+        Err(response) => return response,
+    };
+    let overwrite = match request.headers.get("overwrite") {
+        None => true,
+        Some(value) if value.eq_ignore_ascii_case(b"t") => true,
+        Some(value) if value.eq_ignore_ascii_case(b"f") => false,
+        Some(_) => return bad_request("invalid overwrite"),
+    };
+    let infinite = match depth(request) {
+        Some(Depth::Infinity) => true,
+        Some(Depth::Zero) if !moving => false,
+        _ => return bad_request("invalid depth"),
+    };
+
+    let metadata = match fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::status(404, "Not Found")
+        }
+        Err(err) => return internal_error("reading metadata of", path, err),
+    };
+    if destination == root || destination.starts_with(path) || path == root {
+        return HttpResponse::status(403, "Forbidden");
+    }
+    let protecting = protecting(&state.locks, &destination, true);
+    if let Some(response) = require_tokens(&protecting, submitted) {
+        return response;
+    }
+
+    let existing = fs::symlink_metadata(&destination).await.ok();
+    if existing.is_some() && !overwrite {
+        return precondition_failed();
+    }
+    let parent = destination
+        .parent()
+        .expect("the destination is inside the root");
+    if !fs::metadata(parent)
+        .await
+        .is_ok_and(|parent| parent.is_dir())
+    {
+        return HttpResponse::status(409, "Conflict");
+    }
+
+    // a copy is put together next to the destination, so whatever is there now stays until
+    // the copy is complete
+    let staged = match moving {
+        true => None,
+        false => {
+            let staged = TempFile::next_to(&destination);
+            if let Err(err) = copy(path, staged.path(), infinite, root, options.symlinks).await {
+                return internal_error("copying", path, err);
+            }
+            Some(staged)
+        }
+    };
+
+    // a rename only replaces what's there if neither is a directory, otherwise that's moved
+    // aside first, and back if the transfer fails
+    let replaces_directory = existing
+        .as_ref()
+        .is_some_and(|existing| existing.is_dir() || metadata.is_dir());
+    let aside = match replaces_directory {
+        true => {
+            let aside = TempFile::next_to(&destination);
+            if let Err(err) = fs::rename(&destination, aside.path()).await {
+                return internal_error("replacing", &destination, err);
+            }
+            Some(aside)
+        }
+        false => None,
+    };
+
+    let result = match staged {
+        Some(staged) => staged.persist(&destination).await,
+        None => fs::rename(path, &destination).await,
+    };
+    if let Err(err) = result {
+        if let Some(aside) = aside {
+            let _ = aside.persist(&destination).await;
+        }
+        return internal_error(if moving { "moving" } else { "copying" }, path, err);
+    }
+
+    if let Some(aside) = aside {
+        if let Err(err) = aside.remove().await {
+            log::warn!("Error removing replaced {:?}: {}", destination, err);
+        }
+    }
+    if existing.is_some() {
+        state.forget(&destination);
+    }
+
+    state.carry_properties(path, &destination, moving, infinite || !metadata.is_dir());
+    if moving {
+        // locks stay with the URL they were taken on, rather than move with the file
+        state.locks.remove_within(path);
+    }
+
+    match existing {
+        Some(_) => HttpResponse::status(204, "No Content"),
+        None => HttpResponse::status(201, "Created"),
+    }
+}
+
+/// Copies the file or directory at `from` to `to`, with the directory's contents if
+/// `infinite`. Symbolic links are copied as links, rather than what they point to, if
+/// `symlinks` allows them: with `WithinRoot`, links that don't lead to a file under `root`
+/// from where they were copied to are left out. `to` has to be at the same depth as where the
+/// copy ends up, so relative links lead to the same place from both.
+async fn copy(
+    from: &Path,
+    to: &Path,
+    infinite: bool,
+    root: &Path,
+    symlinks: SymlinkPolicy,
+) -> io::Result<()> {
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+    let mut links = Vec::new();
+
+    while let Some((from, to)) = pending.pop() {
+        let metadata = fs::symlink_metadata(&from).await?;
+        if metadata.is_symlink() {
+            if symlinks != SymlinkPolicy::Deny {
+                fs::symlink(fs::read_link(&from).await?, &to).await?;
+                links.push(to);
+            }
+        } else if metadata.is_dir() {
+            fs::create_dir(&to).await?;
+            if infinite {
+                let mut entries = fs::read_dir(&from).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    pending.push((entry.path(), to.join(entry.file_name())));
+                }
+            }
+        } else {
+            fs::copy(&from, &to).await?;
+        }
+    }
+
+    // links are checked once everything they may lead to has been copied
+    if symlinks == SymlinkPolicy::WithinRoot {
+        for link in links {
+            match fs::canonicalize(&link).await {
+                Ok(target) if target.starts_with(root) => {}
+                _ => fs::remove_file(&link).await?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Responds to a LOCK taking a write lock on the file at `path`, or refreshing one whose
+/// token was submitted if the request has no body. Locking a path where there's no file
+/// creates an empty one (RFC 4918 section 7.3).
+async fn lock(
+    state: &State,
+    request: &mut HttpRequest,
+    root: &Path,
+    path: &Path,
+    submitted: &[String],
+) -> HttpResponse {
+    let timeout = timeout(request);
+    let body = match read_body(request).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    if body.is_empty() {
+        let refreshed = submitted
+            .iter()
+            .find_map(|token| state.locks.refresh(path, token, timeout));
+        return match refreshed {
+            Some(lock) => lock_response(200, "OK", root, &lock, path.is_dir()),
+            None => precondition_failed(),
+        };
+    }
+
+    let info = match parse_body(&body, "lockinfo") {
+        Some(info) => info,
+        None => return bad_request("expected a lockinfo document"),
+    };
+    let scope = info.child(DAV, "lockscope");
+    let exclusive = match (
+        scope.and_then(|scope| scope.child(DAV, "exclusive")),
+        scope.and_then(|scope| scope.child(DAV, "shared")),
+    ) {
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        _ => return bad_request("expected an exclusive or shared lock scope"),
+    };
+    let write = info
+        .child(DAV, "locktype")
+        .and_then(|locktype| locktype.child(DAV, "write"));
+    if write.is_none() {
+        return bad_request("only write locks are supported");
+    }
+    let mut owner = String::new();
+    if let Some(element) = info.child(DAV, "owner") {
+        element.write_content(&mut owner);
+    }
+    let infinite = match depth(request) {
+        Some(Depth::Zero) => false,
+        Some(Depth::Infinity) => true,
+        _ => return bad_request("invalid depth"),
+    };
+
+    let new = Lock::new(path.to_path_buf(), exclusive, infinite, owner, timeout);
+    let lock = match state.locks.lock(new) {
+        Some(lock) => lock,
+        None => return locked(),
+    };
+
+    let created = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)
+        .await;
+    match created {
+        Ok(_) => lock_response(201, "Created", root, &lock, false),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            lock_response(200, "OK", root, &lock, path.is_dir())
+        }
+        Err(err) => {
+            state.locks.unlock(path, &lock.token);
+            match err.kind() {
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => {
+                    HttpResponse::status(409, "Conflict")
+                }
+                _ => internal_error("creating", path, err),
+            }
+        }
+    }
+}
+
+/// The lock timeout the request's `Timeout` field asks for: the first of the ones listed
+/// that's understood, up to the maximum.
+fn timeout(request: &HttpRequest) -> Duration {
+    request
+        .headers
+        .get_list("timeout")
+        .find_map(|timeout| match timeout.split_once('-') {
+            Some((unit, seconds)) if unit.eq_ignore_ascii_case("second") => {
+                seconds.parse().ok().map(Duration::from_secs)
+            }
+            _ if timeout.eq_ignore_ascii_case("infinite") => Some(locks::MAX_TIMEOUT),
+            _ => None,
+        })
+        .map_or(locks::MAX_TIMEOUT, |timeout| {
+            timeout.min(locks::MAX_TIMEOUT)
+        })
+}
+
+fn lock_response(
+    status_code: u16,
+    status_line: &str,
+    root: &Path,
+    lock: &Lock,
+    collection: bool,
+) -> HttpResponse {
+    let mut body = String::from("<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>");
+    write_active_lock(&mut body, root, lock, collection);
+    body.push_str("</D:lockdiscovery></D:prop>");
+    xml_response(status_code, status_line, &body)
+        .with_header("lock-token", &format!("<{}>", lock.token))
+}
+
+fn write_active_lock(out: &mut String, root: &Path, lock: &Lock, collection: bool) {
+    write!(
+        out,
+        "<D:activelock><D:locktype><D:write/></D:locktype>\
+         <D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>",
+        if lock.exclusive {
+            "exclusive"
+        } else {
+            "shared"
+        },
+        if lock.infinite { "infinity" } else { "0" },
+    )
+    .unwrap();
+    if !lock.owner.is_empty() {
+        write!(out, "<D:owner>{}</D:owner>", lock.owner).unwrap();
+    }
+    write!(
+        out,
+        "<D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        lock.remaining().as_secs_f64().ceil(),
+        escape(&lock.token),
+        href(root, &lock.root, collection)
+    )
+    .unwrap();
+}
+
+/// Responds to an UNLOCK removing the lock whose token is in the request's `Lock-Token`
+/// field, which has to apply to `path`.
+fn unlock(state: &State, request: &HttpRequest, path: &Path) -> HttpResponse {
+    let token = request
+        .headers
+        .get("lock-token")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| value.trim().strip_prefix('<')?.strip_suffix('>'));
+
+    match token {
+        Some(token) if state.locks.unlock(path, token) => HttpResponse::status(204, "No Content"),
+        Some(_) => HttpResponse::status(409, "Conflict"),
+        None => bad_request("missing lock-token"),
+    }
+}
+
+/// The URI path of the file at `path`, with a trailing slash for a collection.
+fn href(root: &Path, path: &Path, collection: bool) -> String {
+    let mut href = String::from("/files");
+    for component in path.strip_prefix(root).unwrap_or(Path::new("")) {
+        href.push('/');
+        href.push_str(&percent_encode(&component.to_string_lossy()));
+    }
+    if collection {
+        href.push('/');
+    }
+    href
+}
+
+/// Writes a property in the DAV namespace with the `D` prefix the responses declare, and any
+/// other in its own namespace.
+fn write_property(out: &mut String, name: &Element, value: &str) {
+    if name.namespace != DAV {
+        let mut property = name.name_only();
+        property.text = value.to_string();
+        return property.write(out);
+    }
+
+    match value.is_empty() {
+        true => write!(out, "<D:{}/>", name.name),
+        false => write!(out, "<D:{0}>{1}</D:{0}>", name.name, value),
+    }
+    .unwrap();
+}
+
+/// The body of a 207 Multi-Status response (RFC 4918 section 13), built up one resource at a
+/// time.
+struct Multistatus(String);
+
+impl Multistatus {
+    fn new() -> Self {
+        Self(String::from("<D:multistatus xmlns:D=\"DAV:\">"))
+    }
+
+    /// Adds the properties of the file at `href`, grouped by status code. Groups without any
+    /// properties are left out.
+    fn response(&mut self, href: &str, propstats: &[(u16, String)]) {
+        write!(self.0, "<D:response><D:href>{}</D:href>", href).unwrap();
+        for (status_code, properties) in propstats {
+            if properties.is_empty() {
+                continue;
+            }
+            write!(
+                self.0,
+                "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+                properties,
+                status_code,
+                reason(*status_code)
+            )
+            .unwrap();
+        }
+        self.0.push_str("</D:response>");
+    }
+
+    fn into_response(mut self) -> HttpResponse {
+        self.0.push_str("</D:multistatus>");
+        xml_response(207, "Multi-Status", &self.0)
+    }
+}
+
+fn reason(status_code: u16) -> &'static str {
+    match status_code {
+        200 => "OK",
+        403 => "Forbidden",
+        404 => "Not Found",
+        424 => "Failed Dependency",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+fn xml_response(status_code: u16, status_line: &str, body: &str) -> HttpResponse {
+    HttpResponse {
+        content: Body::Full(format!("{}{}\n", XML_DECLARATION, body).into_bytes()),
+        ..HttpResponse::status(status_code, status_line)
+    }
+    .with_header("content-type", "application/xml; charset=utf-8")
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::error(400, "Bad Request", message.to_string())
+}
+
+fn locked() -> HttpResponse {
+    HttpResponse::error(423, "Locked", "resource is locked".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        listener::{request::RequestReader, testing::TempDir},
+        options::ServerOptions,
+    };
+
+    /// A server's file root, with WebDAV enabled.
+    struct Server {
+        options: ServerOptions,
+        state: State,
+        root: PathBuf,
+        dir: TempDir,
+    }
+
+    impl Server {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(name);
+            let root = dir.path().to_path_buf();
+
+            let mut options = ServerOptions {
+                root: Some(root.clone()),
+                ..ServerOptions::default()
+            };
+            options.files.webdav = true;
+            Self {
+                options,
+                state: State::default(),
+                root,
+                dir,
+            }
+        }
+
+        /// Sends a request with `head` and `body`, returning the response with its content.
+        async fn send(&self, head: &str, body: &str) -> (HttpResponse, String) {
+            let input = format!("{}\r\ncontent-length: {}\r\n\r\n{}", head, body.len(), body);
+            let server = ServerOptions::default();
+            let mut reader = RequestReader::new(input.as_bytes(), server.limits, server.timeouts);
+            let request = reader.read().await.unwrap().unwrap();
+
+            let mut response = handler::handle(self.options.clone(), &self.state, request).await;
+            let content = std::mem::replace(&mut response.content, Body::Full(Vec::new()));
+            let content = String::from_utf8(content.collect().await.unwrap()).unwrap();
+            (response, content)
+        }
+
+        async fn status(&self, head: &str, body: &str) -> u16 {
+            self.send(head, body).await.0.status_code
+        }
+    }
+
+    const EXCLUSIVE: &str = "<?xml version=\"1.0\"?><D:lockinfo xmlns:D=\"DAV:\">\
+        <D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>\
+        <D:owner><D:href>mailto:ada@example.com</D:href></D:owner></D:lockinfo>";
+
+    #[test]
+    fn parse_if_fields() {
+        let lists = parse_if(
+            "</files/a> (<urn:uuid:1> [\"x\"]) (Not <DAV:no-lock>)\t<http://h/files/b> ([W/\"y\"])",
+        )
+        .unwrap();
+        let token = |token: &str| Test::Token(token.to_string());
+        assert_eq!(
+            lists,
+            [
+                IfList {
+                    tag: Some("/files/a".to_string()),
+                    conditions: vec![
+                        Condition {
+                            not: false,
+                            test: token("urn:uuid:1")
+                        },
+                        Condition {
+                            not: false,
+                            test: Test::ETag("\"x\"".to_string())
+                        },
+                    ],
+                },
+                IfList {
+                    tag: Some("/files/a".to_string()),
+                    conditions: vec![Condition {
+                        not: true,
+                        test: token("DAV:no-lock")
+                    }],
+                },
+                IfList {
+                    tag: Some("http://h/files/b".to_string()),
+                    conditions: vec![Condition {
+                        not: false,
+                        test: Test::ETag("W/\"y\"".to_string())
+                    }],
+                },
+            ]
+        );
+
+        for malformed in ["", "()", "(<a>", "<a>", "(a)", "<a> b", "([x)"] {
+            assert_eq!(parse_if(malformed), None, "{}", malformed);
+        }
+    }
+
+    #[tokio::test]
+    async fn create_and_list_collections() {
+        let server = Server::new("collections");
+
+        let (response, _) = server.send("OPTIONS /files/ HTTP/1.1", "").await;
+        assert_eq!(response.header("dav"), Some("1, 2"));
+        assert!(response.header("allow").unwrap().contains("PROPFIND"));
+
+        assert_eq!(server.status("MKCOL /files/docs HTTP/1.1", "").await, 201);
+        assert_eq!(server.status("MKCOL /files/docs HTTP/1.1", "").await, 405);
+        assert_eq!(server.status("MKCOL /files/a/b HTTP/1.1", "").await, 409);
+        assert_eq!(server.status("MKCOL /files/c HTTP/1.1", "<x/>").await, 415);
+        assert!(server.root.join("docs").is_dir());
+
+        let put = "PUT /files/docs/read%20me.txt HTTP/1.1";
+        assert_eq!(server.status(put, "hello").await, 201);
+        std::fs::write(server.root.join("docs/.hidden"), "").unwrap();
+
+        let (response, content) = server
+            .send("PROPFIND /files/docs HTTP/1.1\r\ndepth: 1", "")
+            .await;
+        assert_eq!(response.status_code, 207);
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/xml; charset=utf-8")
+        );
+        assert!(
+            content.contains("<D:href>/files/docs/</D:href>"),
+            "{}",
+            content
+        );
+        assert!(content.contains("<D:resourcetype><D:collection/></D:resourcetype>"));
+        assert!(content.contains("<D:href>/files/docs/read%20me.txt</D:href>"));
+        assert!(content.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(content.contains("<D:getcontenttype>text/plain; charset=utf-8</D:getcontenttype>"));
+        assert!(content.contains("<D:lockentry>"));
+        assert!(!content.contains(".hidden"));
+
+        let head = "PROPFIND /files/docs HTTP/1.1\r\ndepth: 0";
+        let body = "<propfind xmlns=\"DAV:\"><prop><getetag/><resourcetype/>\
+            <color xmlns=\"urn:x\"/></prop></propfind>";
+        let (response, content) = server.send(head, body).await;
+        assert_eq!(response.status_code, 207);
+        assert_eq!(content.matches("<D:response>").count(), 1);
+        assert!(content.contains(
+            "<D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop>\
+             <D:status>HTTP/1.1 200 OK</D:status>"
+        ));
+        assert!(content.contains(
+            "<D:prop><D:getetag/><color xmlns=\"urn:x\"/></D:prop>\
+             <D:status>HTTP/1.1 404 Not Found</D:status>"
+        ));
+
+        assert_eq!(
+            server.status("PROPFIND /files/docs HTTP/1.1", "").await,
+            403
+        );
+        assert_eq!(
+            server
+                .status("PROPFIND /files/none HTTP/1.1\r\ndepth: 0", "")
+                .await,
+            404
+        );
+        let head = "PROPFIND /files/docs HTTP/1.1\r\ndepth: 0";
+        assert_eq!(server.status(head, "<propfind/>").await, 400);
+
+        assert_eq!(server.status("DELETE /files/docs HTTP/1.1", "").await, 204);
+        assert!(!server.root.join("docs").exists());
+    }
+
+    #[tokio::test]
+    async fn set_and_remove_properties() {
+        let server = Server::new("properties");
+        assert_eq!(server.status("PUT /files/a HTTP/1.1", "a").await, 201);
+
+        let set = "<D:propertyupdate xmlns:D=\"DAV:\" xmlns:Z=\"urn:z\">\
+            <D:set><D:prop><Z:color>red <Z:b/></Z:color><Z:size>1</Z:size></D:prop></D:set>\
+            <D:remove><D:prop><Z:size/></D:prop></D:remove></D:propertyupdate>";
+        let (response, content) = server.send("PROPPATCH /files/a HTTP/1.1", set).await;
+        assert_eq!(response.status_code, 207);
+        assert!(
+            content.contains("<D:status>HTTP/1.1 200 OK</D:status>"),
+            "{}",
+            content
+        );
+
+        let head = "PROPFIND /files/a HTTP/1.1\r\ndepth: 0";
+        let (_, content) = server.send(head, "").await;
+        assert!(content.contains("<color xmlns=\"urn:z\">red <b xmlns=\"urn:z\"/></color>"));
+        assert!(!content.contains("size"));
+
+        let (_, content) = server
+            .send(head, "<propfind xmlns=\"DAV:\"><propname/></propfind>")
+            .await;
+        assert!(content.contains("<color xmlns=\"urn:z\"/>"), "{}", content);
+        assert!(content.contains("<D:getetag/>"));
+
+        let protected = "<D:propertyupdate xmlns:D=\"DAV:\"><D:set><D:prop>\
+            <D:getetag>x</D:getetag><Z:color xmlns:Z=\"urn:z\">blue</Z:color>\
+            </D:prop></D:set></D:propertyupdate>";
+        let (response, content) = server.send("PROPPATCH /files/a HTTP/1.1", protected).await;
+        assert_eq!(response.status_code, 207);
+        assert!(content
+            .contains("<D:prop><D:getetag/></D:prop><D:status>HTTP/1.1 403 Forbidden</D:status>"));
+        assert!(content.contains("<D:status>HTTP/1.1 424 Failed Dependency</D:status>"));
+        let (_, content) = server.send(head, "").await;
+        assert!(content.contains(">red <"));
+
+        assert_eq!(server.status("PROPPATCH /files/b HTTP/1.1", set).await, 404);
+        assert_eq!(
+            server.status("PROPPATCH /files/a HTTP/1.1", "<x/>").await,
+            400
+        );
+
+        assert_eq!(server.status("DELETE /files/a HTTP/1.1", "").await, 204);
+        assert_eq!(server.status("PUT /files/a HTTP/1.1", "a").await, 201);
+        let (_, content) = server.send(head, "").await;
+        assert!(!content.contains("color"));
+    }
+
+    #[tokio::test]
+    async fn limit_properties() {
+        let server = Server::new("property-limit");
+        assert_eq!(server.status("PUT /files/a HTTP/1.1", "a").await, 201);
+        let set = |names: std::ops::Range<usize>| {
+            let properties: String = names
+                .map(|name| format!("<p{} xmlns=\"urn:z\">v</p{}>", name, name))
+                .collect();
+            format!(
+                "<propertyupdate xmlns=\"DAV:\"><set><prop>{}</prop></set></propertyupdate>",
+                properties
+            )
+        };
+
+        let (response, _) = server
+            .send("PROPPATCH /files/a HTTP/1.1", &set(0..MAX_PROPERTIES - 1))
+            .await;
+        assert_eq!(response.status_code, 207);
+        let (_, content) = server
+            .send("PROPPATCH /files/a HTTP/1.1", &set(0..MAX_PROPERTIES + 1))
+            .await;
+        let failed = "<p98 xmlns=\"urn:z\"/></D:prop>\
+            <D:status>HTTP/1.1 424 Failed Dependency</D:status>";
+        assert!(content.contains(failed), "{}", content);
+        let insufficient = "<p99 xmlns=\"urn:z\"/><p100 xmlns=\"urn:z\"/></D:prop>\
+            <D:status>HTTP/1.1 507 Insufficient Storage</D:status>";
+        assert!(content.contains(insufficient), "{}", content);
+        assert_eq!(server.state.properties(&server.root.join("a")).len(), 99);
+
+        // those of files deleted other than through WebDAV are dropped eventually
+        for name in 0..MIN_SWEPT {
+            let path = format!("/files/{}", name);
+            assert_eq!(
+                server.status(&format!("PUT {} HTTP/1.1", path), "").await,
+                201
+            );
+            let head = format!("PROPPATCH {} HTTP/1.1", path);
+            assert_eq!(server.status(&head, &set(0..1)).await, 207);
+            std::fs::remove_file(server.root.join(name.to_string())).unwrap();
+        }
+        // the last one was deleted after they were checked
+        assert_eq!(server.state.properties.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn copy_and_move() {
+        let server = Server::new("copy");
+        assert_eq!(server.status("MKCOL /files/src HTTP/1.1", "").await, 201);
+        assert_eq!(server.status("PUT /files/src/a HTTP/1.1", "a").await, 201);
+        let set = "<propertyupdate xmlns=\"DAV:\"><set><prop>\
+            <tag xmlns=\"urn:z\">t</tag></prop></set></propertyupdate>";
+        assert_eq!(
+            server.status("PROPPATCH /files/src/a HTTP/1.1", set).await,
+            207
+        );
+
+        let copy = |destination: &str, extra: &str| {
+            format!(
+                "COPY /files/src HTTP/1.1\r\ndestination: {}{}",
+                destination, extra
+            )
+        };
+        assert_eq!(server.status(&copy("/files/dst", ""), "").await, 201);
+        assert_eq!(std::fs::read(server.root.join("dst/a")).unwrap(), b"a");
+        let (_, content) = server
+            .send("PROPFIND /files/dst/a HTTP/1.1\r\ndepth: 0", "")
+            .await;
+        assert!(content.contains("<tag xmlns=\"urn:z\">t</tag>"));
+
+        let overwrite = "\r\noverwrite: F";
+        let status = server.status(&copy("/files/dst", overwrite), "").await;
+        assert_eq!(status, 412);
+        let absolute = copy("http://localhost:4221/files/dst", "");
+        assert_eq!(server.status(&absolute, "").await, 204);
+        let shallow = copy("/files/empty", "\r\ndepth: 0");
+        assert_eq!(server.status(&shallow, "").await, 201);
+        assert!(std::fs::read_dir(server.root.join("empty"))
+            .unwrap()
+            .next()
+            .is_none());
+
+        assert_eq!(server.status(&copy("/files/src/in", ""), "").await, 403);
+        assert_eq!(server.status(&copy("/elsewhere", ""), "").await, 502);
+        assert_eq!(server.status(&copy("/files/no/dst", ""), "").await, 409);
+        assert_eq!(server.status("COPY /files/src HTTP/1.1", "").await, 400);
+
+        let head = "MOVE /files/src/a HTTP/1.1\r\ndestination: /files/b";
+        assert_eq!(server.status(head, "").await, 201);
+        assert!(!server.root.join("src/a").exists());
+        let (_, content) = server
+            .send("PROPFIND /files/b HTTP/1.1\r\ndepth: 0", "")
+            .await;
+        assert!(content.contains("<tag xmlns=\"urn:z\">t</tag>"));
+        let (_, content) = server
+            .send("PROPFIND /files/src HTTP/1.1\r\ndepth: 1", "")
+            .await;
+        assert!(!content.contains("/files/src/a"));
+
+        let head = "MOVE /files/dst HTTP/1.1\r\ndestination: /files/src\r\ndepth: 0";
+        assert_eq!(server.status(head, "").await, 400);
+    }
+
+    #[tokio::test]
+    async fn keep_destinations_of_failed_copies() {
+        let server = Server::new("failed-copy");
+        assert_eq!(server.status("MKCOL /files/src HTTP/1.1", "").await, 201);
+        assert_eq!(server.status("PUT /files/dst HTTP/1.1", "kept").await, 201);
+        let set = "<propertyupdate xmlns=\"DAV:\"><set><prop>\
+            <tag xmlns=\"urn:z\">t</tag></prop></set></propertyupdate>";
+        assert_eq!(
+            server.status("PROPPATCH /files/dst HTTP/1.1", set).await,
+            207
+        );
+
+        // a socket can't be opened for copying
+        let _socket = std::os::unix::net::UnixListener::bind(server.root.join("src/s")).unwrap();
+        let head = "COPY /files/src HTTP/1.1\r\ndestination: /files/dst";
+        assert_eq!(server.status(head, "").await, 500);
+
+        assert_eq!(std::fs::read(server.root.join("dst")).unwrap(), b"kept");
+        assert_eq!(server.dir.entries(), ["dst", "src"]);
+        let (_, content) = server
+            .send("PROPFIND /files/dst HTTP/1.1\r\ndepth: 0", "")
+            .await;
+        assert!(content.contains("<tag xmlns=\"urn:z\">t</tag>"));
+    }
+
+    #[tokio::test]
+    async fn copy_links_the_policy_allows() {
+        let mut server = Server::new("copy-links");
+        server.dir.write("dir/src/a", "a");
+        let link = |target: &str, name: &str| {
+            std::os::unix::fs::symlink(target, server.root.join("dir/src").join(name)).unwrap()
+        };
+        link("a", "in");
+        // leads to the root from where it is, but above it from a level up
+        link("../..", "up");
+        link("missing", "dangling");
+
+        let head = "COPY /files/dir/src HTTP/1.1\r\ndestination: /files/within";
+        assert_eq!(server.status(head, "").await, 201);
+        let within = server.root.join("within");
+        assert_eq!(
+            std::fs::read_link(within.join("in")).unwrap(),
+            Path::new("a")
+        );
+        assert_eq!(std::fs::read(within.join("in")).unwrap(), b"a");
+        assert!(std::fs::symlink_metadata(within.join("up")).is_err());
+        assert!(std::fs::symlink_metadata(within.join("dangling")).is_err());
+
+        server.options.files.symlinks = SymlinkPolicy::Deny;
+        let head = "COPY /files/dir/src HTTP/1.1\r\ndestination: /files/denied";
+        assert_eq!(server.status(head, "").await, 201);
+        let mut entries: Vec<_> = std::fs::read_dir(server.root.join("denied"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["a"]);
+
+        server.options.files.symlinks = SymlinkPolicy::Follow;
+        let head = "COPY /files/dir/src HTTP/1.1\r\ndestination: /files/followed";
+        assert_eq!(server.status(head, "").await, 201);
+        let up = std::fs::read_link(server.root.join("followed/up")).unwrap();
+        assert_eq!(up, Path::new("../.."));
+    }
+
+    #[tokio::test]
+    async fn lock_and_unlock() {
+        let server = Server::new("locks");
+        assert_eq!(server.status("MKCOL /files/dir HTTP/1.1", "").await, 201);
+
+        let head = "LOCK /files/dir/new HTTP/1.1\r\ntimeout: Second-600\r\ndepth: 0";
+        let (response, content) = server.send(head, EXCLUSIVE).await;
+        assert_eq!(response.status_code, 201);
+        assert_eq!(std::fs::read(server.root.join("dir/new")).unwrap(), b"");
+        let token = response.header("lock-token").unwrap().to_string();
+        assert!(token.starts_with("<urn:uuid:"), "{}", token);
+        assert!(content.contains("<D:lockscope><D:exclusive/></D:lockscope>"));
+        assert!(content.contains("<D:depth>0</D:depth>"));
+        assert!(content.contains("<D:owner><href xmlns=\"DAV:\">mailto:ada@example.com</href>"));
+        assert!(
+            content.contains("<D:timeout>Second-600</D:timeout>"),
+            "{}",
+            content
+        );
+        assert!(content.contains("<D:lockroot><D:href>/files/dir/new</D:href></D:lockroot>"));
+
+        // changing the file, or the directory it's in, takes the lock's token
+        assert_eq!(server.status(head, EXCLUSIVE).await, 423);
+        assert_eq!(server.status("PUT /files/dir/new HTTP/1.1", "x").await, 423);
+        assert_eq!(server.status("DELETE /files/dir HTTP/1.1", "").await, 423);
+        let move_dir = "MOVE /files/dir HTTP/1.1\r\ndestination: /files/moved";
+        assert_eq!(server.status(move_dir, "").await, 423);
+        let copy_over = "COPY /files/dir HTTP/1.1\r\ndestination: /files/dir/new";
+        assert_eq!(server.status(copy_over, "").await, 403);
+        assert_eq!(
+            server.status("PUT /files/dir/other HTTP/1.1", "x").await,
+            201
+        );
+
+        let put = format!("PUT /files/dir/new HTTP/1.1\r\nif: ({})", token);
+        assert_eq!(server.status(&put, "x").await, 204);
+        let tagged = format!(
+            "PUT /files/dir/new HTTP/1.1\r\nif: </files/dir/new> ({})",
+            token
+        );
+        assert_eq!(server.status(&tagged, "y").await, 204);
+        let wrong = "PUT /files/dir/new HTTP/1.1\r\nif: (<urn:uuid:0>)";
+        assert_eq!(server.status(wrong, "z").await, 412);
+        let not = "PUT /files/dir/new HTTP/1.1\r\nif: (Not <urn:uuid:0>)";
+        assert_eq!(server.status(not, "z").await, 423);
+
+        let (_, content) = server
+            .send("PROPFIND /files/dir/new HTTP/1.1\r\ndepth: 0", "")
+            .await;
+        assert!(
+            content.contains("<D:lockdiscovery><D:activelock>"),
+            "{}",
+            content
+        );
+
+        let refresh = format!("LOCK /files/dir/new HTTP/1.1\r\nif: ({})", token);
+        let (response, content) = server.send(&refresh, "").await;
+        assert_eq!(response.status_code, 200);
+        assert!(
+            content.contains("<D:timeout>Second-3600</D:timeout>"),
+            "{}",
+            content
+        );
+        let refresh = format!("{}\r\ntimeout: second-x, SECOND-60, Infinite", refresh);
+        let (_, content) = server.send(&refresh, "").await;
+        assert!(
+            content.contains("<D:timeout>Second-60</D:timeout>"),
+            "{}",
+            content
+        );
+        let refresh = "LOCK /files/dir/new HTTP/1.1\r\nif: (Not <urn:uuid:0>)";
+        assert_eq!(server.status(refresh, "").await, 412);
+
+        let unlock = "UNLOCK /files/dir/new HTTP/1.1\r\nlock-token: <urn:uuid:0>";
+        assert_eq!(server.status(unlock, "").await, 409);
+        assert_eq!(
+            server.status("UNLOCK /files/dir/new HTTP/1.1", "").await,
+            400
+        );
+        let unlock = format!("UNLOCK /files/dir/new HTTP/1.1\r\nlock-token: {}", token);
+        assert_eq!(server.status(&unlock, "").await, 204);
+        assert_eq!(server.status(&unlock, "").await, 409);
+        assert_eq!(server.status("PUT /files/dir/new HTTP/1.1", "z").await, 204);
+
+        // a lock on a directory with infinite depth covers everything in it
+        let shared = EXCLUSIVE.replace("exclusive", "shared");
+        let (response, _) = server.send("LOCK /files/dir HTTP/1.1", &shared).await;
+        assert_eq!(response.status_code, 200);
+        let token = response.header("lock-token").unwrap().to_string();
+        assert_eq!(
+            server.status("LOCK /files/dir HTTP/1.1", &shared).await,
+            200
+        );
+        let head = "LOCK /files/dir/new HTTP/1.1\r\ndepth: 0";
+        assert_eq!(server.status(head, EXCLUSIVE).await, 423);
+        assert_eq!(server.status("PUT /files/dir/new HTTP/1.1", "w").await, 423);
+        let put = format!("PUT /files/dir/new HTTP/1.1\r\nif: ({})", token);
+        assert_eq!(server.status(&put, "w").await, 204);
+
+        let head = "LOCK /files/dir HTTP/1.1\r\ndepth: 1";
+        assert_eq!(server.status(head, &shared).await, 400);
+        assert_eq!(
+            server
+                .status("LOCK /files/dir HTTP/1.1", "<lockinfo/>")
+                .await,
+            400
+        );
+    }
+}
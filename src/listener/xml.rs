@@ -0,0 +1,202 @@
+//! A minimal XML element tree, for the bodies of WebDAV requests and the properties they set.
+
+use crate::listener::listing::escape;
+use quick_xml::{
+    events::Event,
+    name::{Namespace, ResolveResult},
+    NsReader,
+};
+
+/// The namespace of WebDAV's own elements and properties.
+pub const DAV: &str = "DAV:";
+
+/// How deeply elements may be nested. Properties are stored and written back out, so this
+/// keeps a client from making that arbitrarily expensive.
+const MAX_DEPTH: usize = 32;
+
+/// An element, with its namespace resolved. Attributes are dropped, none of the elements in
+/// WebDAV requests use any, and the text of mixed content is joined together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Element {
+    pub namespace: String,
+    pub name: String,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn new(namespace: &str, name: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            children: Vec::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Parses a document into its root element. Returns `None` if it isn't well-formed
+    /// UTF-8 XML, or uses a document type declaration, which could define entities.
+    pub fn parse(document: &[u8]) -> Option<Self> {
+        let mut reader = NsReader::from_str(std::str::from_utf8(document).ok()?);
+        let mut open: Vec<Element> = Vec::new();
+
+        loop {
+            let (namespace, event) = reader.read_resolved_event().ok()?;
+            let element = |name: &[u8]| -> Option<Element> {
+                let namespace = match namespace {
+                    ResolveResult::Bound(Namespace(namespace)) => std::str::from_utf8(namespace),
+                    ResolveResult::Unbound => Ok(""),
+                    ResolveResult::Unknown(_) => return None,
+                };
+                Some(Element::new(
+                    namespace.ok()?,
+                    std::str::from_utf8(name).ok()?,
+                ))
+            };
+
+            match event {
+                Event::Start(start) => {
+                    if open.len() == MAX_DEPTH {
+                        return None;
+                    }
+                    open.push(element(start.local_name().into_inner())?);
+                }
+                Event::Empty(empty) => {
+                    let element = element(empty.local_name().into_inner())?;
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Some(element),
+                    }
+                }
+                Event::End(_) => {
+                    let element = open.pop()?;
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Some(element),
+                    }
+                }
+                Event::Text(text) => {
+                    let text = text.unescape().ok()?;
+                    match open.last_mut() {
+                        Some(element) => element.text.push_str(&text),
+                        None if text.trim().is_empty() => {}
+                        None => return None,
+                    }
+                }
+                Event::CData(data) => {
+                    let data = std::str::from_utf8(&data).ok()?.to_string();
+                    open.last_mut()?.text.push_str(&data);
+                }
+                Event::DocType(_) | Event::Eof => return None,
+                Event::Comment(_) | Event::Decl(_) | Event::PI(_) => {}
+            }
+        }
+    }
+
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+
+    /// The element without its content, to name a property.
+    pub fn name_only(&self) -> Self {
+        Self::new(&self.namespace, &self.name)
+    }
+
+    /// Writes the element out, declaring its namespace as the default one so its name
+    /// doesn't depend on any prefixes in scope.
+    pub fn write(&self, out: &mut String) {
+        out.push('<');
+        out.push_str(&self.name);
+        out.push_str(" xmlns=\"");
+        out.push_str(&escape(&self.namespace));
+        out.push('"');
+
+        if self.children.is_empty() && self.text.is_empty() {
+            out.push_str("/>");
+            return;
+        }
+
+        out.push('>');
+        self.write_content(out);
+        out.push_str("</");
+        out.push_str(&self.name);
+        out.push('>');
+    }
+
+    /// Writes out what's inside the element: its text, then its children.
+    pub fn write_content(&self, out: &mut String) {
+        out.push_str(&escape(&self.text));
+        for child in &self.children {
+            child.write(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_elements_with_namespaces() {
+        let document = br#"<?xml version="1.0" encoding="utf-8"?>
+            <D:propfind xmlns:D="DAV:" xmlns="http://example.com/ns">
+              <D:prop><D:getetag/><color>red &amp; <![CDATA[<blue>]]></color><plain xmlns=""/></D:prop>
+            </D:propfind>"#;
+
+        let root = Element::parse(document).unwrap();
+        assert!(root.is(DAV, "propfind"));
+        let prop = root.child(DAV, "prop").unwrap();
+        let names: Vec<_> = prop
+            .children
+            .iter()
+            .map(|child| (child.namespace.as_str(), child.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (DAV, "getetag"),
+                ("http://example.com/ns", "color"),
+                ("", "plain")
+            ]
+        );
+        assert_eq!(prop.children[1].text, "red & <blue>");
+
+        let mut written = String::new();
+        prop.children[1].write(&mut written);
+        assert_eq!(
+            written,
+            r#"<color xmlns="http://example.com/ns">red &amp; &lt;blue&gt;</color>"#
+        );
+        assert_eq!(
+            Element::parse(written.as_bytes()),
+            Some(prop.children[1].clone())
+        );
+    }
+
+    #[test]
+    fn reject_malformed_documents() {
+        let documents: [&[u8]; 6] = [
+            b"",
+            b"<a><b></a>",
+            b"<x:a/>",
+            b"text<a/>",
+            b"<!DOCTYPE a [<!ENTITY e \"e\">]><a>&e;</a>",
+            b"<a>\xff</a>",
+        ];
+
+        for document in documents {
+            assert_eq!(Element::parse(document), None, "{:?}", document);
+        }
+
+        let nested = format!(
+            "{}{}",
+            "<a>".repeat(MAX_DEPTH + 1),
+            "</a>".repeat(MAX_DEPTH + 1)
+        );
+        assert_eq!(Element::parse(nested.as_bytes()), None);
+    }
+}
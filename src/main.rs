@@ -1,12 +1,74 @@
 // Uncomment this block to pass the first stage
-use std::io::Result;
+use options::{Command, ServerOptions};
+use std::{env, io::Result, process};
 
+mod config;
 mod listener;
+mod log;
 mod options;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let options = options::ServerOptions::new();
-    let handle = listener::start("127.0.0.1:4221", options);
+    let options = match ServerOptions::from_args(env::args().skip(1)) {
+        Ok(Command::Serve(options)) => options,
+        Ok(Command::CheckConfig(options)) => {
+            let addrs: Vec<_> = options.addrs().iter().map(|a| a.to_string()).collect();
+            println!("configuration OK, would listen on {}", addrs.join(", "));
+            return Ok(());
+        }
+        Ok(Command::Help) => {
+            print!("{}", options::USAGE);
+            return Ok(());
+        }
+        Ok(Command::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!(
+                "error: {}\nrun with --help to see the available options",
+                err
+            );
+            process::exit(2);
+        }
+    };
+
+    log::set_max_level(options.log_level);
+    match &options.root {
+        Some(root) => log::info!("serving files from directory {:?}", root),
+        None => log::info!("--directory not provided, file serving disabled"),
+    }
+
+    for addr in options.addrs() {
+        log::info!("listening on {}", addr);
+    }
+
+    let handle = listener::start(options.addrs(), options, shutdown_signal());
     handle.await.unwrap()
 }
+
+/// Completes when the process is asked to stop, with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                log::warn!("failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
@@ -1,45 +1,621 @@
+use crate::{config::ConfigFile, listener::mime, log::Level};
 use std::{
-    env,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
+use thiserror::Error;
 
-#[derive(Clone)]
+pub const USAGE: &str = concat!(
+    "Usage: ",
+    env!("CARGO_PKG_NAME"),
+    " [OPTIONS]
+
+Options:
+  -c, --config <FILE>               Read options from a TOML file; other options given on
+                                    the command line take precedence over it
+      --check-config                Validate the options and configuration file, then exit
+  -b, --bind <ADDRESS>              IP address to listen on, may be repeated
+                                    [default: 127.0.0.1]
+  -p, --port <PORT>                 Port to listen on [default: 4221]
+  -d, --directory <DIR>             Serve and store files under /files/ from DIR
+      --symlinks <POLICY>           Which symbolic links under DIR may be used: deny,
+                                    within-root or follow [default: within-root]
+      --mime-type <EXT=TYPE>        Serve files ending in .EXT as TYPE, may be repeated
+      --sniff                       Guess the type of files without an extension from
+                                    their content
+      --listings                    List the contents of directories under DIR
+      --show-hidden                 Include files whose names start with a dot in
+                                    directory listings
+      --sendfile                    Send file contents with sendfile(2), on Linux
+      --fsync                       Sync uploaded files to disk before answering
+      --create-dirs                 Create missing directories for uploaded files
+      --webdav                      Serve DIR over WebDAV as well, at /files/
+      --log-level <LEVEL>           One of off, error, warn, info or debug [default: info]
+      --max-request-line <SIZE>     Longest accepted request line [default: 8K]
+      --max-header-size <SIZE>      Largest accepted header section [default: 64K]
+      --max-headers <COUNT>         Most header fields accepted in a request [default: 100]
+      --max-body-size <SIZE>        Largest accepted request body [default: 64M]
+      --idle-timeout <DURATION>     Close connections idle for this long [default: 60s]
+      --header-timeout <DURATION>   Time allowed to send request headers [default: 10s]
+      --min-body-rate <SIZE>        Slowest accepted body upload rate per second, 0 to
+                                    disable [default: 1K]
+      --body-grace <DURATION>       Time before the minimum body rate applies [default: 10s]
+      --shutdown-timeout <DURATION> Time allowed for requests to finish on shutdown
+                                    [default: 30s]
+      --no-compression              Never compress responses
+      --compression-level <LEVEL>   From 0 (fastest) to 9 (smallest) [default: 6]
+      --compression-min-size <SIZE> Send smaller responses uncompressed [default: 0]
+      --decode-requests             Decompress request bodies sent with a content coding
+      --max-decoded-size <SIZE>     Largest accepted request body after decompressing it
+                                    [default: 64M]
+      --max-decode-ratio <RATIO>    Most a request body may grow by when decompressing it
+                                    [default: 100]
+  -h, --help                        Print this help and exit
+  -V, --version                     Print the version and exit
+
+SIZE is a number of bytes, optionally followed by K, M or G. DURATION is a number of
+seconds, optionally followed by ms, s or m to pick the unit.
+"
+);
+
+#[derive(Clone, Debug)]
 pub struct ServerOptions {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
     pub root: Option<PathBuf>,
+    pub log_level: Level,
+    pub limits: RequestLimits,
+    pub timeouts: Timeouts,
+    pub compression: CompressionOptions,
+    pub files: FileOptions,
+}
+
+/// What the command line asked the server to do.
+#[derive(Debug)]
+pub enum Command {
+    Serve(ServerOptions),
+    CheckConfig(ServerOptions),
+    Help,
+    Version,
+}
+
+#[derive(Error, Debug)]
+pub enum OptionsError {
+    #[error("unknown option {0:?}")]
+    UnknownOption(String),
+    #[error("{0} requires a value")]
+    MissingValue(String),
+    #[error("{0} doesn't take a value")]
+    UnexpectedValue(String),
+    #[error("invalid value {value:?} for {option}: {reason}")]
+    InvalidValue {
+        option: String,
+        value: String,
+        reason: String,
+    },
+    #[error("can't serve files from {path:?}: {reason}")]
+    InvalidDirectory { path: PathBuf, reason: String },
+    #[error("invalid configuration file {path:?}: {reason}")]
+    InvalidConfig { path: PathBuf, reason: String },
+}
+
+/// Upper bounds on the size of incoming requests, enforced while they are being read.
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    /// Maximum length of the request line, including the line terminator.
+    pub max_request_line: usize,
+    /// Maximum combined length of all header lines, including line terminators.
+    pub max_header_size: usize,
+    /// Maximum number of header fields.
+    pub max_headers: usize,
+    /// Maximum size of a request body, after removing any chunked transfer coding.
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_size: 64 * 1024,
+            max_headers: 100,
+            max_body_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Bounds on how long clients may take to send requests, so slow or stalled clients can't hold
+/// on to a connection indefinitely.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// How long a connection may stay open without the client starting a new request.
+    pub idle: Duration,
+    /// How long a client may take to send the request line and headers, once it starts.
+    pub headers: Duration,
+    /// Minimum average rate a request body has to be sent at, in bytes per second. Zero
+    /// disables the check.
+    pub min_body_rate: u64,
+    /// How long a request body may take before the minimum rate starts to apply.
+    pub body_grace: Duration,
+    /// How long to wait for requests in progress to finish when shutting down.
+    pub shutdown: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            headers: Duration::from_secs(10),
+            min_body_rate: 1024,
+            body_grace: Duration::from_secs(10),
+            shutdown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressionOptions {
+    /// Whether responses may be compressed for clients that accept it.
+    pub enabled: bool,
+    /// Compression level, from 0 (fastest) to 9 (smallest).
+    pub level: u32,
+    /// Responses with less content than this are sent uncompressed, as compressing them
+    /// saves too little to be worth it.
+    pub min_size: usize,
+    /// Whether request bodies sent with a content coding are decompressed before handling.
+    pub decode_requests: bool,
+    /// Maximum size of a decompressed request body.
+    pub max_decoded_size: usize,
+    /// Maximum factor a request body may grow by when decompressed.
+    pub max_decode_ratio: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            level: 6,
+            min_size: 0,
+            decode_requests: false,
+            max_decoded_size: 64 * 1024 * 1024,
+            max_decode_ratio: 100,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FileOptions {
+    /// Which symbolic links requests for files may go through.
+    pub symlinks: SymlinkPolicy,
+    /// Media types by lowercase file extension, overriding the built-in ones.
+    pub mime_types: HashMap<String, String>,
+    /// Whether the type of files without an extension is guessed from their content.
+    pub sniff: bool,
+    /// Whether requests for directories get a listing of their contents.
+    pub listings: bool,
+    /// Whether listings include files whose names start with a dot.
+    pub show_hidden: bool,
+    /// Whether file contents are sent with `sendfile(2)`, where that's available.
+    pub sendfile: bool,
+    /// Whether uploaded files are synced to disk before they're reported as stored.
+    pub fsync: bool,
+    /// Whether uploads create the directories their files go in when those don't exist.
+    pub create_dirs: bool,
+    /// Whether WebDAV methods are handled for files, so clients can mount the root directory.
+    pub webdav: bool,
+}
+
+/// How symbolic links under the root directory are treated when serving or storing files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// No path component under the root may be a link.
+    Deny,
+    /// Links may be used as long as they lead to somewhere under the root.
+    #[default]
+    WithinRoot,
+    /// Links may lead anywhere.
+    Follow,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deny" => Ok(Self::Deny),
+            "within-root" => Ok(Self::WithinRoot),
+            "follow" => Ok(Self::Follow),
+            _ => Err("expected one of deny, within-root or follow".to_string()),
+        }
+    }
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: 4221,
+            root: None,
+            log_level: Level::Info,
+            limits: RequestLimits::default(),
+            timeouts: Timeouts::default(),
+            compression: CompressionOptions::default(),
+            files: FileOptions::default(),
+        }
+    }
 }
 
 impl ServerOptions {
-    pub fn new() -> Self {
-        let args = env::args().collect::<Vec<_>>();
-        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
-
-        match args[1..] {
-            ["--directory", directory] => {
-                let path: &Path = directory.as_ref();
-                match path.canonicalize() {
-                    Ok(path) if path.is_dir() => {
-                        println!("serving files from directory {:?}", path);
-                        Self { root: Some(path) }
-                    }
-                    Ok(path) => {
-                        println!(
-                            "{path:?} does not exist or is not a directory; file serving disabled"
-                        );
-                        Self { root: None }
-                    }
-                    Err(err) => {
-                        println!(
-                            "failed to canonicalize directory, file serving disabled: {}",
-                            err
-                        );
-                        Self { root: None }
+    /// Parses command line arguments, not including the program name. Options may appear in
+    /// any order, with their value either as the next argument or after an `=`. If a
+    /// configuration file is given, it's applied first, and the other options override it.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, OptionsError> {
+        let args = args.into_iter().collect::<Vec<_>>();
+        let mut options = Self::default();
+
+        if let Some(path) = config_path(&args)? {
+            ConfigFile::load(path.as_ref())?.apply(&mut options)?;
+        }
+
+        let mut args = args.into_iter();
+        let mut bind_given = false;
+        let mut check_config = false;
+
+        while let Some(arg) = args.next() {
+            let (option, mut value) = match arg.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => {
+                    (option.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+
+            let is_flag = matches!(
+                option.as_str(),
+                "-h" | "--help"
+                    | "-V"
+                    | "--version"
+                    | "--check-config"
+                    | "--no-compression"
+                    | "--decode-requests"
+                    | "--sniff"
+                    | "--listings"
+                    | "--show-hidden"
+                    | "--sendfile"
+                    | "--fsync"
+                    | "--create-dirs"
+                    | "--webdav"
+            );
+            if is_flag && value.is_some() {
+                return Err(OptionsError::UnexpectedValue(option));
+            }
+
+            let mut value = || match value.take() {
+                Some(value) => Ok(value),
+                None => args
+                    .next()
+                    .ok_or_else(|| OptionsError::MissingValue(option.clone())),
+            };
+
+            match option.as_str() {
+                "-h" | "--help" => return Ok(Command::Help),
+                "-V" | "--version" => return Ok(Command::Version),
+                "-c" | "--config" => {
+                    // already applied
+                    value()?;
+                }
+                "--check-config" => check_config = true,
+                "-b" | "--bind" => {
+                    // addresses on the command line replace those in the configuration file
+                    if !bind_given {
+                        options.bind.clear();
+                        bind_given = true;
                     }
+                    options.bind.push(parse(&option, value()?)?);
+                }
+                "-p" | "--port" => options.port = parse(&option, value()?)?,
+                "-d" | "--directory" => options.root = Some(directory(value()?.as_ref())?),
+                "--symlinks" => options.files.symlinks = parse(&option, value()?)?,
+                "--mime-type" => {
+                    let value = value()?;
+                    let (extension, media_type) = value
+                        .split_once('=')
+                        .ok_or_else(|| invalid(&option, &value, "expected EXT=TYPE".to_string()))?;
+                    let (extension, media_type) = mime_type(extension, media_type)
+                        .map_err(|reason| invalid(&option, &value, reason))?;
+                    options.files.mime_types.insert(extension, media_type);
+                }
+                "--sniff" => options.files.sniff = true,
+                "--listings" => options.files.listings = true,
+                "--show-hidden" => options.files.show_hidden = true,
+                "--sendfile" => options.files.sendfile = true,
+                "--fsync" => options.files.fsync = true,
+                "--create-dirs" => options.files.create_dirs = true,
+                "--webdav" => options.files.webdav = true,
+                "--log-level" => options.log_level = parse(&option, value()?)?,
+                "--max-request-line" => {
+                    options.limits.max_request_line = parse_size(&option, value()?)?
+                }
+                "--max-header-size" => {
+                    options.limits.max_header_size = parse_size(&option, value()?)?
                 }
+                "--max-headers" => options.limits.max_headers = parse(&option, value()?)?,
+                "--max-body-size" => options.limits.max_body_size = parse_size(&option, value()?)?,
+                "--idle-timeout" => options.timeouts.idle = parse_duration(&option, value()?)?,
+                "--header-timeout" => options.timeouts.headers = parse_duration(&option, value()?)?,
+                "--min-body-rate" => {
+                    options.timeouts.min_body_rate = parse_size(&option, value()?)? as u64
+                }
+                "--body-grace" => options.timeouts.body_grace = parse_duration(&option, value()?)?,
+                "--shutdown-timeout" => {
+                    options.timeouts.shutdown = parse_duration(&option, value()?)?
+                }
+                "--no-compression" => options.compression.enabled = false,
+                "--compression-level" => {
+                    options.compression.level = compression_level(&option, value()?)?
+                }
+                "--compression-min-size" => {
+                    options.compression.min_size = parse_size(&option, value()?)?
+                }
+                "--decode-requests" => options.compression.decode_requests = true,
+                "--max-decoded-size" => {
+                    options.compression.max_decoded_size = parse_size(&option, value()?)?
+                }
+                "--max-decode-ratio" => {
+                    options.compression.max_decode_ratio = parse(&option, value()?)?
+                }
+                _ => return Err(OptionsError::UnknownOption(option)),
             }
+        }
+
+        if check_config {
+            Ok(Command::CheckConfig(options))
+        } else {
+            Ok(Command::Serve(options))
+        }
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.bind
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .collect()
+    }
+}
+
+/// Finds the configuration file given on the command line, if any.
+fn config_path(args: &[String]) -> Result<Option<&str>, OptionsError> {
+    let mut path = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(value) => path = Some(value.as_str()),
+                None => return Err(OptionsError::MissingValue(arg.clone())),
+            },
             _ => {
-                println!("--directory not provided, file serving disabled");
-                Self { root: None }
+                if let Some(value) = arg.strip_prefix("--config=") {
+                    path = Some(value);
+                }
             }
         }
     }
+
+    Ok(path)
+}
+
+fn parse<T>(option: &str, value: String) -> Result<T, OptionsError>
+where
+    T: FromStr,
+    T::Err: ToString,
+{
+    value
+        .parse::<T>()
+        .map_err(|err| invalid(option, &value, err.to_string()))
+}
+
+fn parse_size(option: &str, value: String) -> Result<usize, OptionsError> {
+    size(&value).map_err(|reason| invalid(option, &value, reason))
+}
+
+fn parse_duration(option: &str, value: String) -> Result<Duration, OptionsError> {
+    duration(&value).map_err(|reason| invalid(option, &value, reason))
+}
+
+fn compression_level(option: &str, value: String) -> Result<u32, OptionsError> {
+    level(&value).map_err(|reason| invalid(option, &value, reason))
+}
+
+/// Checks a media type for files with an extension, and normalizes the extension.
+pub fn mime_type(extension: &str, media_type: &str) -> Result<(String, String), String> {
+    let extension = extension
+        .trim()
+        .trim_start_matches('.')
+        .to_ascii_lowercase();
+    if extension.is_empty() || extension.contains(['/', '.']) {
+        return Err(format!("invalid file extension {:?}", extension));
+    }
+    if !mime::is_valid(media_type) {
+        return Err(format!("invalid media type {:?}", media_type));
+    }
+
+    Ok((extension, media_type.trim().to_string()))
+}
+
+/// Parses a compression level.
+pub fn level(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(level @ 0..=9) => Ok(level),
+        _ => Err("expected a level from 0 to 9".to_string()),
+    }
+}
+
+/// Parses a number of bytes, with an optional K, M or G suffix for powers of 1024.
+pub fn size(value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+
+    let multiplier: usize = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err("unknown size unit".to_string()),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| "expected a number of bytes".to_string())
+}
+
+/// Longest accepted timeout. Deadlines are computed by adding timeouts to the current time,
+/// which has to stay representable.
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parses a duration in seconds, or in the unit given by an ms, s or m suffix, up to
+/// `MAX_DURATION`.
+pub fn duration(value: &str) -> Result<Duration, String> {
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, "s"),
+    };
+
+    let amount = digits
+        .parse::<u64>()
+        .map_err(|_| "expected a number".to_string())?;
+
+    let duration = match unit {
+        "ms" => Duration::from_millis(amount),
+        "s" => Duration::from_secs(amount),
+        "m" => amount
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| "duration too large".to_string())?,
+        _ => return Err("unknown duration unit".to_string()),
+    };
+
+    match duration <= MAX_DURATION {
+        true => Ok(duration),
+        false => Err("duration too large".to_string()),
+    }
+}
+
+/// Resolves the directory files are served from, which has to exist.
+pub fn directory(path: &Path) -> Result<PathBuf, OptionsError> {
+    match path.canonicalize() {
+        Ok(path) if path.is_dir() => Ok(path),
+        Ok(path) => Err(OptionsError::InvalidDirectory {
+            path,
+            reason: "not a directory".to_string(),
+        }),
+        Err(err) => Err(OptionsError::InvalidDirectory {
+            path: path.to_path_buf(),
+            reason: err.to_string(),
+        }),
+    }
+}
+
+fn invalid(option: &str, value: &str, reason: String) -> OptionsError {
+    OptionsError::InvalidValue {
+        option: option.to_string(),
+        value: value.to_string(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serve(args: &[&str]) -> Result<ServerOptions, OptionsError> {
+        match ServerOptions::from_args(args.iter().map(|arg| arg.to_string()))? {
+            Command::Serve(options) => Ok(options),
+            other => panic!("expected options, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_defaults() {
+        let options = serve(&[]).unwrap();
+        assert_eq!(options.addrs(), vec!["127.0.0.1:4221".parse().unwrap()]);
+        assert_eq!(options.root, None);
+        assert_eq!(options.log_level, Level::Info);
+    }
+
+    #[test]
+    fn parse_options_in_any_order() {
+        let options = serve(&[
+            "--max-body-size=2M",
+            "-p",
+            "8080",
+            "--directory",
+            ".",
+            "--idle-timeout",
+            "500ms",
+            "--bind=0.0.0.0",
+            "--log-level",
+            "debug",
+            "--header-timeout",
+            "5",
+            "--compression-level",
+            "9",
+            "--mime-type",
+            ".LOG=text/plain",
+        ])
+        .unwrap();
+
+        assert_eq!(options.addrs(), vec!["0.0.0.0:8080".parse().unwrap()]);
+        assert_eq!(options.root, Some(Path::new(".").canonicalize().unwrap()));
+        assert_eq!(options.log_level, Level::Debug);
+        assert_eq!(options.limits.max_body_size, 2 * 1024 * 1024);
+        assert_eq!(options.timeouts.idle, Duration::from_millis(500));
+        assert_eq!(options.timeouts.headers, Duration::from_secs(5));
+        assert_eq!(options.compression.level, 9);
+        assert_eq!(options.files.mime_types["log"], "text/plain");
+    }
+
+    #[test]
+    fn parse_help_and_version() {
+        let args = ["--port", "80", "--help"].map(String::from);
+        assert!(matches!(ServerOptions::from_args(args), Ok(Command::Help)));
+
+        let args = ["-V"].map(String::from);
+        assert!(matches!(
+            ServerOptions::from_args(args),
+            Ok(Command::Version)
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_arguments() {
+        let cases: &[&[&str]] = &[
+            &["--frobnicate"],
+            &["extra"],
+            &["--port"],
+            &["--port", "65536"],
+            &["--bind", "localhost:80"],
+            &["--max-body-size", "12T"],
+            &["--idle-timeout", "1h"],
+            &["--idle-timeout", "999999999999999999m"],
+            &["--header-timeout", "18446744073709551615s"],
+            &["--compression-level", "10"],
+            &["--symlinks", "sometimes"],
+            &["--mime-type", "txt"],
+            &["--mime-type", "txt=plain"],
+            &["--mime-type", "=text/plain"],
+            &["--directory", "/does/not/exist"],
+            &["--directory", "Cargo.toml"],
+            &["--help=yes"],
+        ];
+
+        for args in cases {
+            assert!(serve(args).is_err(), "{:?}", args);
+        }
+    }
 }